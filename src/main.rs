@@ -1,39 +1,198 @@
 use std::borrow::Cow;
 use std::io::{BufRead, Read, Write};
-use std::time::SystemTime;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime};
 
 use base64::Engine;
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use fastly::erl::{CounterDuration, Penaltybox, RateCounter};
 use fastly::handle::BodyHandle;
 use fastly::http::{Method, header};
-use fastly::kv_store::InsertMode;
-use fastly::{Error, KVStore, Request, Response, cache, mime};
+use fastly::kv_store::{InsertMode, KVStoreError};
+use fastly::secret_store::SecretStore;
+use fastly::{Body, ConfigStore, Error, KVStore, Request, Response, cache, mime};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
 use humanize_bytes::humanize_bytes_binary;
 use humantime::format_duration;
+use img_parts::{Bytes, DynImage, ImageEXIF};
 use pad::PadStr;
 use serde_json::json;
-use types::FileMetadata;
+use similar::TextDiff;
+use types::{FileMetadata, MissingStore, Page, PasteError, PopularPaste, StorageFull};
+
+/// Correlation id for the current request, included in every log line
+static REQUEST_ID: OnceLock<String> = OnceLock::new();
+
+/// Print a log line, prefixed with the request id, or as a json object with json logs enabled
+macro_rules! log {
+    ($($arg:tt)*) => {{
+        let request_id = REQUEST_ID.get().map(String::as_str).unwrap_or("-");
+        if config::JSON_LOGS {
+            println!(
+                "{}",
+                serde_json::json!({ "request_id": request_id, "msg": format!($($arg)*) })
+            )
+        } else {
+            println!("[{request_id}] {}", format_args!($($arg)*))
+        }
+    }};
+}
 
 mod config {
     use std::time::Duration;
 
     /// Upload ID length, up to 64 bytes
     pub const ID_SIZE: usize = 8;
+    /// Base58 alphabet for upload IDs, ie bitcoin (default), flickr, or ripple. IDs can only be
+    /// verified with standard base58 tools when using the bitcoin alphabet.
+    pub const ID_ALPHABET: &[u8; 58] =
+        b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+    /// Hostname to redirect page views to, when the service is reachable on multiple hosts
+    pub const CANONICAL_HOST: Option<&str> = None;
+    /// Fastly config store name, for runtime overrides
+    pub const CONFIG_STORE: &str = "paste config";
+    /// Config store key to override the upload ID length
+    pub const ID_SIZE_KEY: &str = "id_size";
+    /// Use random ids instead of content addressing for all uploads, also enabled with `?random`
+    pub const RANDOM_IDS: bool = false;
+    /// Attempts to generate an unused random id
+    pub const RANDOM_ID_ATTEMPTS: usize = 8;
     /// Minimum content size in bytes
     pub const MIN_CONTENT_SIZE: usize = 32;
     /// Maximum content size in bytes
     pub const MAX_CONTENT_SIZE: usize = 24 << 20;
-    /// Fastly key-value storage name
+    /// Maximum number of files stored from an unpacked archive
+    pub const MAX_UNPACK_FILES: usize = 32;
+    /// Maximum total size of the files unpacked from an archive in bytes
+    pub const MAX_UNPACK_SIZE: usize = MAX_CONTENT_SIZE;
+    /// Maximum number of pastes downloaded together as a zip archive
+    pub const MAX_ZIP_IDS: usize = 32;
+    /// Fastly key-value storage name, also holding the metrics
     pub const KV_STORE: &str = "paste storage";
+    /// Key-value stores to shard pastes across by id. Changing the order or
+    /// number of shards moves existing pastes, so only append before launch.
+    pub const KV_SHARDS: &[&str] = &[KV_STORE];
     /// TTL for content
     pub const KV_TTL: Duration = Duration::from_secs(14 * 86400);
     /// Request cache ttl
     pub const CACHE_TTL: Duration = Duration::from_secs(90 * 86400);
     /// Key to store upload metrics under
     pub const UPLOAD_METRICS_KEY: &str = "_upload_metrics";
+    /// Key to store the number of uploads of each mime type under
+    pub const MIME_METRICS_KEY: &str = "_upload_metrics_mime";
+    /// Maximum number of mime types counted, uploads of any others are counted as `other`
+    pub const MAX_METRICS_MIMES: usize = 64;
+    /// Attempts at updating a metrics count while other uploads update it concurrently
+    pub const METRICS_RETRIES: usize = 5;
+    /// Key to store the number of uploads in each size bucket under
+    pub const SIZE_METRICS_KEY: &str = "_upload_metrics_size";
+    /// Upper bounds of the upload size buckets in bytes, up to the maximum content size
+    pub const SIZE_BUCKETS: &[usize] = &[1 << 10, 1 << 20, MAX_CONTENT_SIZE];
+    /// Prefix of the keys counting uploads each day, ie `_uploads_20261231`
+    pub const DAILY_METRICS_KEY: &str = "_uploads";
+    /// Number of days of uploads kept and reported
+    pub const DAILY_METRICS_DAYS: u64 = 14;
+    /// Maximum total bytes stored across all pastes, before uploads are rejected
+    pub const MAX_TOTAL_BYTES: Option<u64> = None;
+    /// Key to store the sizes and expiry times of stored pastes under
+    pub const STORAGE_LEDGER_KEY: &str = "_storage_ledger";
+    /// Chance (one in n uploads) of removing expired pastes from the storage ledger
+    pub const STORAGE_RECONCILE_RATE: u32 = 100;
+    /// Fastly rate counter name for abuse reports
+    pub const REPORT_RATE_COUNTER: &str = "report counter";
+    /// Fastly penalty box name for abuse reports
+    pub const REPORT_PENALTY_BOX: &str = "report penalty box";
+    /// Maximum number of reports per client ip in a minute
+    pub const REPORT_RATE_LIMIT: u32 = 5;
+    /// Duration clients are blocked from reporting after exceeding the limit
+    pub const REPORT_PENALTY_TTL: Duration = Duration::from_secs(10 * 60);
+    /// Maximum length of a shortened url in bytes
+    pub const MAX_URL_SIZE: usize = 2048;
+    /// Maximum length of a stored filename in bytes
+    pub const MAX_FILENAME_SIZE: usize = 255;
+    /// Maximum length of a paste title in characters
+    pub const MAX_TITLE_SIZE: usize = 128;
+    /// Maximum length of a slug derived from the first line of a text paste in bytes
+    pub const MAX_SLUG_SIZE: usize = 48;
+    /// Maximum length of a report reason in bytes
+    pub const MAX_REPORT_SIZE: usize = 512;
+    /// Strip exif metadata (gps, camera, timestamps) from jpeg, png, and webp uploads
+    pub const STRIP_EXIF: bool = true;
+    /// Remove scripts, event handlers, and external references from svg uploads
+    pub const SANITIZE_SVG: bool = true;
+    /// Prefer a specific content type given on upload over the detected one, when it's plausible
+    pub const RESPECT_CONTENT_TYPE: bool = true;
+    /// Strip the utf-8 byte order mark from text uploads, ie from windows editors
+    pub const STRIP_BOM: bool = true;
+    /// Default width of image paste thumbnails in pixels
+    pub const THUMBNAIL_WIDTH: u32 = 320;
+    /// Maximum width (and height) of image paste thumbnails in pixels
+    pub const MAX_THUMBNAIL_WIDTH: u32 = 1024;
+    /// Maximum memory used to decode an image for a thumbnail in bytes
+    pub const MAX_THUMBNAIL_ALLOC: u64 = 64 * 1024 * 1024;
+    /// Use a hardened content security policy, which only allows resources from this origin and
+    /// the script cdn, instead of any origin
+    pub const STRICT_CSP: bool = false;
+    /// Content security policy style sources, overriding the default of the policy profile
+    pub const CSP_STYLE_SRC: Option<&str> = None;
+    /// Url browsers report content security policy violations to, disabled when unset
+    pub const CSP_REPORT_URI: Option<&str> = None;
+    /// Maximum number of origins a paste can allow embedding it in frames
+    pub const MAX_EMBED_ORIGINS: usize = 8;
+    /// Fastly secret store name
+    pub const SECRET_STORE: &str = "paste secrets";
+    /// Secret used to sign expiring paste urls
+    pub const SIGNING_SECRET: &str = "signing_key";
+    /// Default lifetime for signed urls
+    pub const SIGNED_URL_TTL: Duration = Duration::from_secs(86400);
+    /// Encrypt stored content with a service key, in addition to the storage provider
+    pub const ENCRYPT_AT_REST: bool = false;
+    /// Secret used to encrypt content at rest
+    pub const STORAGE_SECRET: &str = "storage_key";
+    /// Secret bearer token for the operator endpoint
+    pub const ADMIN_SECRET: &str = "admin_token";
+    /// Number of recent uploads listed on the operator endpoint
+    pub const ADMIN_LIST_SIZE: usize = 100;
+    /// Number of characters of text content previewed in paste json
+    pub const PREVIEW_SIZE: usize = 200;
+    /// Number of headings before rendered markdown includes a table of contents
+    pub const TOC_MIN_HEADINGS: usize = 6;
+    /// Store text content compressed with gzip
+    pub const COMPRESS_TEXT: bool = true;
+    /// Fastly backend to notify of new uploads, disabled when unset
+    pub const WEBHOOK_BACKEND: Option<&str> = None;
+    /// Url to post new upload notifications to, on the webhook backend
+    pub const WEBHOOK_URL: &str = "https://hooks.example.com/0dd";
+    /// Emit structured json log lines, instead of human readable lines
+    pub const JSON_LOGS: bool = false;
+    /// Redirect paste ids with the wrong case to the stored id, when there's only one match
+    pub const CASE_INSENSITIVE_IDS: bool = true;
+    /// Keep a tombstone for deleted pastes, so they respond with 410 instead of 404
+    pub const TOMBSTONES: bool = true;
+    /// Namespace pastes and metrics by host, for multiple pastebins on one deployment
+    pub const MULTI_TENANT: bool = false;
+    /// Record when pastes were last downloaded, in a key next to the paste. Costs a kv lookup
+    /// on every download, and a write at most once per resolution.
+    pub const TRACK_ACCESS: bool = false;
+    /// Minimum time between updates of a paste's last accessed time
+    pub const ACCESS_RESOLUTION: Duration = Duration::from_secs(3600);
+    /// Count the downloads of each paste, and rank the most popular listed pastes. Costs an
+    /// append and a lookup on every download.
+    pub const TRACK_VIEWS: bool = false;
+    /// Update the popular ranking on 1 in this many counted views, since every paste shares it
+    pub const POPULAR_RANK_RATE: u32 = 20;
+    /// Key to store the ranking of the most viewed pastes under
+    pub const POPULAR_KEY: &str = "_popular";
+    /// Number of pastes kept in the popular ranking
+    pub const POPULAR_SIZE: usize = 20;
 }
 
 mod types {
     use std::borrow::Cow;
+    use std::time::SystemTime;
 
     use serde::{Deserialize, Serialize};
 
@@ -41,14 +200,89 @@ mod types {
     pub struct FileMetadata<'a> {
         pub hash: [u8; 32],
         pub mime: Cow<'a, str>,
+        /// Content length in bytes
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub size: Option<usize>,
+        /// Upload time in seconds since the unix epoch
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub created: Option<u64>,
+        /// Image width and height, if the upload is an image
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub dims: Option<(u32, u32)>,
+        /// Paste is only served with a valid signed url
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pub signed: bool,
+        /// Client side encryption scheme (ie, `age`), content is always served raw
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub encrypted: Option<String>,
+        /// Encoding of the stored content (ie, `gzip`)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub encoding: Option<String>,
+        /// Encoded content length in bytes, if the stored content is encoded
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub encoded_size: Option<usize>,
+        /// Blake3 hash of the management token, the token itself is never stored
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub manage: Option<[u8; 32]>,
+        /// Title given by the uploader, shown instead of the filename in html views
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub title: Option<String>,
+        /// Filename given in the upload url, for downloads without one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub filename: Option<String>,
+        /// Deletion time in seconds since the unix epoch, if the paste is a tombstone
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub deleted: Option<u64>,
+        /// Expiry time in seconds since the unix epoch, if given on upload
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub expires: Option<u64>,
+        /// Number of downloads before the paste is deleted
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub max_views: Option<u64>,
+        /// Nonce of the stored content, if it's encrypted at rest
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub nonce: Option<[u8; 12]>,
+        /// Paste is excluded from the upload metrics and listings
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pub unlisted: bool,
+        /// Kind of paste, ie `redirect` for shortened links
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub kind: Option<String>,
+        /// Svg content had its scripts and external references removed on upload
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pub sanitized: bool,
+        /// Origins allowed to embed the paste in frames, or `*` for any
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub embed: Option<String>,
     }
 
     impl FileMetadata<'_> {
         #[inline(always)]
-        pub fn new(hash: [u8; 32], mime: String) -> Self {
+        pub fn new(hash: [u8; 32], mime: String, size: usize) -> Self {
             Self {
                 hash,
                 mime: Cow::Owned(mime),
+                size: Some(size),
+                created: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .ok()
+                    .map(|d| d.as_secs()),
+                dims: None,
+                signed: false,
+                encrypted: None,
+                encoding: None,
+                encoded_size: None,
+                manage: None,
+                title: None,
+                filename: None,
+                deleted: None,
+                expires: None,
+                max_views: None,
+                nonce: None,
+                unlisted: false,
+                kind: None,
+                sanitized: false,
+                embed: None,
             }
         }
 
@@ -57,62 +291,428 @@ mod types {
             &self.mime
         }
     }
+
+    /// Entry in the ranking of the most viewed pastes
+    #[derive(Serialize, Deserialize)]
+    pub struct PopularPaste {
+        pub id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub filename: Option<String>,
+        pub views: u64,
+        /// Unix timestamp the paste expires from storage
+        pub expires: u64,
+    }
+
+    /// Context and view options for rendered html pages
+    pub struct Page<'a> {
+        /// Service hostname
+        pub host: &'a str,
+        /// Page title, ie the filename in the url, unless the paste has a stored title
+        pub title: Option<&'a str>,
+        /// Color scheme override (`dark` or `light`), or empty to follow the browser
+        pub theme: &'a str,
+        /// Always include a table of contents in rendered markdown
+        pub toc: bool,
+        /// Render `$...$` and `$$...$$` latex math in markdown with katex
+        pub math: bool,
+        /// Per response nonce allowed to run scripts by the content security policy
+        pub nonce: &'a str,
+        /// Client accepts brotli, so cached rendered pages are served compressed
+        pub brotli: bool,
+    }
+
+    /// Reasons a paste can't be served
+    pub enum PasteError {
+        /// Paste is missing or expired
+        NotFound,
+        /// Paste was blocked by an operator
+        Blocked,
+        /// Signed url is missing, expired, or invalid
+        InvalidSignature,
+        /// Paste existed, but was deleted
+        Deleted,
+        /// Paste is binary, but was requested as a text view
+        NotText,
+        /// Storage isn't configured for the service
+        Unavailable,
+        /// Storage, cache, or decryption failed while loading the paste
+        Failed(fastly::Error),
+    }
+
+    impl<E: Into<fastly::Error>> From<E> for PasteError {
+        #[inline(always)]
+        fn from(e: E) -> Self {
+            Self::Failed(e.into())
+        }
+    }
+
+    /// A store the service depends on isn't linked, ie a deployment misconfiguration
+    #[derive(Debug)]
+    pub struct MissingStore(pub &'static str);
+
+    impl std::fmt::Display for MissingStore {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "kv store {} is not configured", self.0)
+        }
+    }
+
+    impl std::error::Error for MissingStore {}
+
+    /// A paste doesn't fit in the storage left, as stored after compression and encryption
+    #[derive(Debug)]
+    pub struct StorageFull;
+
+    impl std::fmt::Display for StorageFull {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "storage is full")
+        }
+    }
+
+    impl std::error::Error for StorageFull {}
 }
 
 #[fastly::main]
 fn main(req: Request) -> Result<Response, Error> {
-    println!(
+    let nonce = csp_nonce();
+
+    // Honor a client supplied request id, otherwise generate one
+    let request_id = req
+        .get_header_str("x-request-id")
+        .map(str::trim)
+        .filter(|v| {
+            !v.is_empty()
+                && v.len() <= 64
+                && v.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+    let request_id = REQUEST_ID.get_or_init(|| request_id);
+
+    log!(
         "service version {}",
         std::env::var("FASTLY_SERVICE_VERSION").unwrap_or_default()
     );
+    let start = Instant::now();
+    let method = req.get_method_str().to_string();
+    let path = req.get_path().to_string();
+
+    // Browsers navigating to errors get an html page instead of plain text
+    let error_page = (req.get_method() == Method::GET && is_browser(&req)).then(|| {
+        (
+            req.get_url().host_str().unwrap_or_default().to_string(),
+            get_theme(&req),
+        )
+    });
+
+    // Api clients asking for json get errors as json objects instead
+    let json_errors = req
+        .get_header_str(header::ACCEPT)
+        .unwrap_or_default()
+        .split(',')
+        .any(|m| m.split(';').next().unwrap_or_default().trim() == "application/json");
 
-    let nonce = rand::random::<usize>();
+    // Persist a color scheme override from the query
+    let theme_cookie = req
+        .get_query_parameter("theme")
+        .and_then(|theme| match theme {
+            "dark" | "light" => Some(format!(
+                "theme={theme}; Path=/; Max-Age=31536000; SameSite=Lax; Secure"
+            )),
+            "auto" => Some("theme=; Path=/; Max-Age=0; SameSite=Lax; Secure".to_string()),
+            _ => None,
+        });
 
-    let mut res = match req.get_method() {
-        &Method::PUT => handle_put(req)?,
-        &Method::GET | &Method::HEAD => handle_get(req, nonce)?,
-        _ => Response::from_status(403).with_body("invalid request"),
+    let res = route(req, &nonce);
+    // Misconfigured deployments are reported, instead of failing with an opaque error
+    let mut res = match res {
+        Err(e) if e.is::<MissingStore>() => {
+            log!("{e}");
+            Response::from_status(503).with_body_text_plain(&format!("{e}\n"))
+        },
+        Err(e) if e.is::<StorageFull>() => {
+            Response::from_status(507).with_body_text_plain(&format!("{e}\n"))
+        },
+        res => res?,
     };
 
-    // Enable fastly dynamic compression
-    res.set_header("x-compress-hint", "on");
+    if let Some((host, theme)) = error_page {
+        res = with_error_page(res, &host, theme);
+    } else if json_errors {
+        res = with_json_error(res);
+    }
+
+    // Cookies are never stored by shared caches along with a response
+    if let Some(cookie) = theme_cookie {
+        res.set_header(header::SET_COOKIE, cookie);
+        res.set_header(header::CACHE_CONTROL, cache_control(None));
+    }
+
+    // Enable fastly dynamic compression, unless the content is already compressed
+    if !res.contains_header(header::CONTENT_ENCODING) {
+        res.set_header("x-compress-hint", "on");
+    }
+
+    res.set_header("x-request-id", request_id);
 
     // Enable HSTS for 6mo
     res.set_header(header::STRICT_TRANSPORT_SECURITY, "max-age=15768000");
 
     // Allow CORS, deny CORP unless same origin
     res.set_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*");
+    res.set_header(
+        header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        [
+            "x-request-id",
+            "x-paste-id",
+            "x-paste-hash",
+            "x-expires",
+            "x-expires-at",
+            "x-expires-in",
+            "x-encrypted",
+            "x-image-width",
+            "x-image-height",
+            "x-views",
+        ]
+        .join(", "),
+    );
     res.set_header("cross-origin-resource-policy", "same-origin");
 
     // On same-origin send full referrer header, only send url for others
     res.set_header(header::REFERRER_POLICY, "strict-origin-when-cross-origin");
 
-    // Disable content sniffing, external iframe embeds
+    // Disable content sniffing, external iframe embeds unless the paste allows them
+    let frame_ancestors = res.remove_header_str("x-frame-ancestors");
     res.set_header(header::X_CONTENT_TYPE_OPTIONS, "nosniff");
-    res.set_header(header::X_FRAME_OPTIONS, "SAMEORIGIN");
+    if frame_ancestors.is_none() {
+        res.set_header(header::X_FRAME_OPTIONS, "SAMEORIGIN");
+    }
 
-    // - Allow static external resources
-    // - Allow external and inline styles
-    // - deny objects and embeds
-    // - deny all scripts
-    // - deny all frame ancestors
-    res.set_header(
-        header::CONTENT_SECURITY_POLICY,
-        [
-            "default-src *",
-            "frame-ancestors 'none'",
-            "object-src 'none'",
-            "base-uri 'none'",
-            "form-action 'none'",
-            "style-src * 'unsafe-inline'",
-            &format!("script-src 'nonce-{nonce}'"),
-        ]
-        .join(";"),
-    );
+    // Responses with their own stricter policy keep it, with the paste's frame ancestors
+    match (
+        res.get_header_str(header::CONTENT_SECURITY_POLICY),
+        &frame_ancestors,
+    ) {
+        (None, _) => res.set_header(
+            header::CONTENT_SECURITY_POLICY,
+            content_security_policy(&nonce, frame_ancestors.as_deref().unwrap_or("'none'")),
+        ),
+        (Some(policy), Some(origins)) => {
+            let policy = format!("{policy}; frame-ancestors {origins}");
+            res.set_header(header::CONTENT_SECURITY_POLICY, policy);
+        },
+        (Some(_), None) => {},
+    }
 
+    log_request(&method, &path, &res, start.elapsed());
     Ok(res)
 }
 
+/// Route a request to its handler
+#[inline(always)]
+fn route(req: Request, nonce: &str) -> Result<Response, Error> {
+    // Requests without a host (ie from some http/1.0 proxies) can't be routed to a tenant
+    if req.get_url().host_str().unwrap_or_default().is_empty() {
+        Ok(Response::from_status(400).with_body_text_plain("missing host"))
+    } else if let Some(location) = canonical_redirect(&req) {
+        Ok(Response::from_status(301).with_header(header::LOCATION, location))
+    } else if req.get_path() == "/admin" || req.get_path().starts_with("/admin/") {
+        handle_admin(req)
+    } else if req.get_method() == Method::POST && req.get_path().starts_with("/purge/") {
+        handle_purge(req)
+    } else {
+        match req.get_method() {
+            &Method::PUT => handle_put(req),
+            &Method::POST => handle_post(req),
+            &Method::DELETE => handle_delete(req),
+            &Method::GET | &Method::HEAD => handle_get(req, nonce),
+            _ => Ok(Response::from_status(405)
+                .with_header(header::ALLOW, "GET, HEAD, PUT, POST, DELETE")
+                .with_body("invalid request")),
+        }
+    }
+}
+
+/// Set the cache headers for embedded static assets
+#[inline(always)]
+fn with_static_headers(res: Response) -> Response {
+    res.with_header(
+        header::CACHE_CONTROL,
+        "public, s-maxage=31536000, immutable",
+    )
+}
+
+/// Suggest the paste url for a path that looks like a paste id missing the `/p/` prefix. Only
+/// suggested when the paste actually exists.
+#[inline(always)]
+fn suggest_paste_url(host: &str, id: &str, path: &str) -> Option<String> {
+    if id.len() != id_size() || decode_id(id).is_none() {
+        return None;
+    }
+    kv_for(id)
+        .ok()?
+        .lookup(&tenant_key("file", host, id))
+        .ok()
+        .map(|_| format!("https://{host}/p{path}"))
+}
+
+/// Get the head shared by the plain html pages, ie the icons, font, and color scheme
+#[inline(always)]
+fn page_head() -> &'static str {
+    include_str!("templates/head.html").trim()
+}
+
+/// Wrap a plain text error response with the html error page
+#[inline(always)]
+fn with_error_page(mut res: Response, host: &str, theme: &str) -> Response {
+    let status = res.get_status();
+    let is_text = res
+        .get_content_type()
+        .is_some_and(|m| m.essence_str() == mime::TEXT_PLAIN);
+    if !(status.is_client_error() || status.is_server_error()) || !is_text {
+        return res;
+    }
+
+    let message = res.take_body_str();
+    let html = format!(
+        include_str!("templates/error.html"),
+        head = page_head(),
+        host = host,
+        theme = theme,
+        status = status.as_u16(),
+        reason = status.canonical_reason().unwrap_or_default(),
+        message = htmlescape::encode_minimal(message.trim()).replace('\n', "\n     "),
+    );
+    res.with_body_text_html(&html)
+}
+
+/// Replace a plain text error with a json object, ie `{"error": "content too large", ...}`. Any
+/// lines after the first are included as details.
+#[inline(always)]
+fn with_json_error(mut res: Response) -> Response {
+    let status = res.get_status();
+    let is_text = res
+        .get_content_type()
+        .is_some_and(|m| m.essence_str() == mime::TEXT_PLAIN);
+    if !(status.is_client_error() || status.is_server_error()) || !is_text {
+        return res;
+    }
+
+    let message = res.take_body_str();
+    let mut lines = message.trim().lines();
+    let mut json = json!({
+        "error": lines.next().unwrap_or_else(|| status.canonical_reason().unwrap_or_default()),
+        "status": status.as_u16(),
+    });
+    let details: Vec<_> = lines.map(str::trim).filter(|l| !l.is_empty()).collect();
+    if !details.is_empty() {
+        json["details"] = details.into();
+    }
+    if status.as_u16() == 413 {
+        json["max_bytes"] = config::MAX_CONTENT_SIZE.into();
+    }
+    if let Some(secs) = res
+        .get_header_str(header::RETRY_AFTER)
+        .and_then(|v| v.parse::<u64>().ok())
+    {
+        json["retry_after"] = secs.into();
+    }
+    res.with_body(json.to_string())
+        .with_content_type(mime::APPLICATION_JSON)
+}
+
+/// Get the url on the canonical host for page views on other hosts. Uploads are accepted on any
+/// host.
+#[inline(always)]
+fn canonical_redirect(req: &Request) -> Option<String> {
+    let canonical = config::CANONICAL_HOST?;
+    let is_view = matches!(req.get_method(), &Method::GET | &Method::HEAD);
+    if !is_view || req.get_url().host_str() == Some(canonical) {
+        return None;
+    }
+    let query = req
+        .get_query_str()
+        .map(|q| format!("?{q}"))
+        .unwrap_or_default();
+    Some(format!("https://{canonical}{}{query}", req.get_path()))
+}
+
+/// Log a summary of the request and response
+#[inline(always)]
+fn log_request(method: &str, path: &str, res: &Response, duration: Duration) {
+    let status = res.get_status().as_u16();
+    let size = res.get_content_length();
+    if config::JSON_LOGS {
+        println!(
+            "{}",
+            json!({
+                "request_id": REQUEST_ID.get(),
+                "method": method,
+                "path": path,
+                "status": status,
+                "size": size,
+                "duration_us": duration.as_micros() as u64,
+            })
+        );
+    } else {
+        log!(
+            "{method} {path} {status} {} {duration:?}",
+            size.map(|s| s.to_string()).unwrap_or("-".into())
+        );
+    }
+}
+
+/// Generate an unguessable script nonce for a response, from 16 bytes of os randomness
+#[inline(always)]
+fn csp_nonce() -> String {
+    let mut bytes = [0u8; 16];
+    rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut bytes);
+    base64::engine::general_purpose::STANDARD.encode(bytes)
+}
+
+/// Build the default content security policy, with a nonce for the scripts of this response.
+///
+/// - Allow static external resources (or only this origin and the cdn, when strict)
+/// - Allow external and inline styles
+/// - deny objects and embeds
+/// - deny all scripts without the nonce
+/// - deny all frame ancestors, unless a paste allows some
+#[inline(always)]
+fn content_security_policy(nonce: &str, frame_ancestors: &str) -> String {
+    const CDN: &str = "https://cdn.jsdelivr.net";
+
+    let mut directives = if config::STRICT_CSP {
+        vec![
+            "default-src 'self'".to_string(),
+            "img-src 'self' data:".to_string(),
+            format!("font-src {CDN}"),
+            format!(
+                "style-src {}",
+                config::CSP_STYLE_SRC.unwrap_or(&format!("'self' 'unsafe-inline' {CDN}"))
+            ),
+        ]
+    } else {
+        vec![
+            "default-src *".to_string(),
+            format!(
+                "style-src {}",
+                config::CSP_STYLE_SRC.unwrap_or("* 'unsafe-inline'")
+            ),
+        ]
+    };
+    directives.extend([
+        format!("frame-ancestors {frame_ancestors}"),
+        "object-src 'none'".to_string(),
+        "base-uri 'none'".to_string(),
+        "form-action 'none'".to_string(),
+        format!("script-src 'nonce-{nonce}'"),
+    ]);
+    if let Some(uri) = config::CSP_REPORT_URI {
+        directives.push(format!("report-uri {uri}"));
+    }
+    directives.join(";")
+}
+
 /// Handle a request to put a paste into storage
 #[inline(always)]
 fn handle_put(mut req: Request) -> Result<Response, Error> {
@@ -121,327 +721,4782 @@ fn handle_put(mut req: Request) -> Result<Response, Error> {
         return Ok(Response::from_status(400).with_body_text_plain("missing upload body"));
     }
     let body = req.take_body_bytes();
-    if body.len() < config::MIN_CONTENT_SIZE && body != b"testing\n" {
+
+    // Decode compressed uploads, so the id and mime reflect the real content
+    let encoding = req
+        .get_header_str(header::CONTENT_ENCODING)
+        .map(|e| e.trim().to_ascii_lowercase());
+    let body = match decode_upload(body, encoding.as_deref()) {
+        Ok(body) => body,
+        Err((status, msg)) => return Ok(Response::from_status(status).with_body_text_plain(&msg)),
+    };
+
+    // Shortened links are only a url, which are usually smaller than the minimum
+    let is_shorten = req.get_path() == "/u" || has_query_flag(&req, "shorten");
+    if !is_shorten && body.len() < config::MIN_CONTENT_SIZE && body != b"testing\n" {
         return Ok(Response::from_status(400).with_body_text_plain("content too small"));
     }
     if body.len() > config::MAX_CONTENT_SIZE {
         return Ok(Response::from_status(413).with_body_text_plain("content too large"));
     }
 
+    // Verify the body against the client's expected hash, before any modifications
+    if let Some(expected) = req.get_header_str("x-expected-hash") {
+        let computed = format!(
+            "blake3-{}",
+            base64::engine::general_purpose::STANDARD.encode(blake3::hash(&body).as_bytes())
+        );
+        if expected.trim() != computed {
+            return Ok(Response::from_status(422).with_body_text_plain(&format!(
+                "hash mismatch\nexpected: {}\ncomputed: {computed}\n",
+                expected.trim()
+            )));
+        }
+    }
+
+    // Archives are stored as a paste per file, listed by an index paste
+    if !is_shorten && has_query_flag(&req, "unpack") {
+        return handle_unpack(&req, &body);
+    }
+
+    // Validate and store shortened links without surrounding whitespace
+    let body = if is_shorten {
+        match shorten_target(&body) {
+            Some(target) => target.into_bytes(),
+            None => {
+                return Ok(Response::from_status(400).with_body_text_plain("invalid url"));
+            },
+        }
+    } else {
+        body
+    };
+
+    // Remove exif metadata before hashing, so the id reflects the cleaned image
+    let body = if !is_shorten && config::STRIP_EXIF && infer::is_image(&body) {
+        strip_exif(body)
+    } else {
+        body
+    };
+
+    // Byte order marks show as a stray glyph and hide shebangs, so the id is of the text without
+    let body = if !is_shorten && config::STRIP_BOM && !req.contains_header("x-encrypted") {
+        strip_bom(body)
+    } else {
+        body
+    };
+
+    // Optionally convert crlf and cr line endings to lf, which changes the id so it's opt in
+    let normalize_eol = match req.get_header_str("x-eol").map(str::trim) {
+        Some(eol) if eol.eq_ignore_ascii_case("lf") => true,
+        Some(_) => {
+            return Ok(Response::from_status(400).with_body_text_plain("expected x-eol: lf"));
+        },
+        None => has_query_flag(&req, "normalize-eol"),
+    };
+    let body = match std::str::from_utf8(&body) {
+        Ok(text)
+            if normalize_eol
+                && !is_shorten
+                && !req.contains_header("x-encrypted")
+                && text.contains('\r') =>
+        {
+            normalize_line_endings(text).into_bytes()
+        },
+        _ => body,
+    };
+
     let url = req.get_url();
-    let host = url.host().unwrap().to_string();
+    let host = url.host_str().unwrap_or_default().to_string();
     let filename = url
         .path_segments()
-        .unwrap()
+        .into_iter()
+        .flatten()
         .next_back()
-        .and_then(|v| (!v.is_empty()).then_some(v));
+        .and_then(|v| (!v.is_empty() && url.path() != "/u").then_some(v));
+    // Tools that name uploads with a header instead of the url
+    let header_filename = filename
+        .is_none()
+        .then(|| req.get_header_str(header::CONTENT_DISPOSITION))
+        .flatten()
+        .and_then(disposition_filename);
+    let filename = filename.or(header_filename.as_deref());
+
+    // try and detect mime type from magic byte sequences
+    let content_type = req.get_header_str(header::CONTENT_TYPE);
+    let detected = upload_mime(content_type, &body, filename);
+
+    // Svgs render inline in browsers, so they're cleaned before hashing as well. Content stored
+    // as an svg is always cleaned, whether it was sniffed or named like one.
+    let sanitized =
+        !is_shorten && !req.contains_header("x-encrypted") && is_unsafe_svg(&body, &detected);
+    let body = if sanitized {
+        match sanitize_svg(&body) {
+            Some(body) => body,
+            None => return Ok(Response::from_status(400).with_body_text_plain("invalid svg")),
+        }
+    } else {
+        body
+    };
+
+    // Optionally name text uploads after their first line, for more memorable links
+    let slug = (filename.is_none()
+        && !is_shorten
+        && !req.contains_header("x-encrypted")
+        && has_query_flag(&req, "slug"))
+    .then(|| slugify(&body))
+    .flatten();
+    let filename = filename.or(slug.as_deref());
+
+    // Client side encryption scheme, ie `age` or `gpg`
+    let encrypted = match req.get_header_str("x-encrypted").map(str::trim) {
+        Some(scheme)
+            if !scheme.is_empty()
+                && scheme.len() <= 16
+                && scheme
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-') =>
+        {
+            Some(scheme.to_ascii_lowercase())
+        },
+        Some(_) => {
+            return Ok(Response::from_status(400).with_body_text_plain("invalid encryption scheme"));
+        },
+        None => None,
+    };
+
+    // Optional title for html views, flattened to a single line
+    let title = req
+        .get_header_str("x-title")
+        .map(|v| {
+            v.replace(['\r', '\n'], " ")
+                .trim()
+                .chars()
+                .take(config::MAX_TITLE_SIZE)
+                .collect::<String>()
+        })
+        .filter(|v| !v.is_empty());
+
+    // Optionally allow embedding in frames on other origins, ie `https://a.com https://b.com`
+    let embed = match req.get_header_str("x-allow-embed").map(parse_embed_origins) {
+        Some(Some(origins)) => Some(origins),
+        Some(None) => {
+            return Ok(Response::from_status(400).with_body_text_plain(&format!(
+                "expected * or up to {} origins to allow embedding",
+                config::MAX_EMBED_ORIGINS
+            )));
+        },
+        None => None,
+    };
+
+    // Optionally require a signed url for downloads, expiring after the given seconds
+    let signed_ttl = req
+        .get_url()
+        .query_pairs()
+        .find(|(k, _)| k == "signed")
+        .map(|(_, v)| {
+            v.parse()
+                .map(Duration::from_secs)
+                .unwrap_or(config::SIGNED_URL_TTL)
+                .min(config::KV_TTL)
+        });
+
+    // Optionally expire at a wall clock time, ie "2026-12-31T23:59:59Z", before the storage ttl
+    let expires = match req.get_header_str("x-expire-at").map(str::trim) {
+        Some(v) => match humantime::parse_rfc3339_weak(v)
+            .map(|t| t.duration_since(SystemTime::now()))
+        {
+            Ok(Ok(ttl)) => Some(SystemTime::now() + ttl.min(config::KV_TTL)),
+            Ok(Err(_)) => {
+                return Ok(Response::from_status(400).with_body_text_plain("expiry is in the past"));
+            },
+            Err(_) => {
+                return Ok(Response::from_status(400).with_body_text_plain("invalid expiry time"));
+            },
+        },
+        None => None,
+    };
+
+    // Optionally delete the paste after a number of downloads
+    let max_views = match req
+        .get_header_str("x-max-views")
+        .map(|v| v.trim().parse::<u64>())
+    {
+        Some(Ok(views)) if views > 0 => Some(views),
+        Some(_) => {
+            return Ok(Response::from_status(400).with_body_text_plain("invalid max views"));
+        },
+        None => None,
+    };
+
+    // Optionally exclude the paste from the upload metrics and listings
+    let unlisted = is_unlisted(&req);
 
     // Hash content and use a section of base58 encoding for the id
     let hash = blake3::hash(&body);
-    let base = bs58::encode(hash.as_bytes()).into_string();
-    let id = &base[..config::ID_SIZE];
-    let key = &format!("file_{id}");
+    let base = encode_id(hash.as_bytes());
+    // Random ids are never deduplicated, otherwise reuse an existing copy of the content, even
+    // if it was stored with another id size. Pastes with upload options always get a random id, so
+    // they're never handed out for uploads of the same content.
+    let is_random = config::RANDOM_IDS
+        || has_query_flag(&req, "random")
+        || signed_ttl.is_some()
+        || expires.is_some()
+        || max_views.is_some()
+        || encrypted.is_some()
+        || unlisted;
+    let id = if is_random {
+        random_id(&host)?
+    } else {
+        find_canonical_id(&kv_for(&base)?, &host, &base, &hash)
+            .unwrap_or_else(|| base[..id_size().min(base.len())].to_string())
+    };
+    let id = id.as_str();
+    let kv = kv_for(id)?;
+    let key = &tenant_key("file", &host, id);
 
     // Insert content to key value store
-    let kv = KVStore::open(config::KV_STORE)?.expect("kv store to exist");
-    if kv.lookup(key).is_err() {
-        // try and detect mime type from magic byte sequences
-        let mime = infer::get(&body).map(|t| t.to_string()).unwrap_or_else(|| {
-            // try to detect from the (optionally) given filename
-            if let Some(mime) = filename.and_then(|f| mime_guess::from_path(f).into_iter().next()) {
-                mime.to_string()
-            } else if std::str::from_utf8(&body).is_ok() {
-                // if it's valid utf-8
-                mime::TEXT_PLAIN_UTF_8.to_string()
-            } else {
-                // fallback to raw octet stream bytes
-                mime::APPLICATION_OCTET_STREAM.to_string()
-            }
-        });
+    // Tombstones of deleted pastes are overwritten
+    let existing = kv
+        .lookup(key)
+        .ok()
+        .and_then(|v| v.metadata())
+        .and_then(|m| serde_json::from_slice::<FileMetadata>(&m).ok())
+        .filter(|m| m.deleted.is_none());
+
+    // Different content with the same truncated id is never overwritten
+    if let Some(existing) = existing
+        .as_ref()
+        .filter(|m| blake3::Hash::from(m.hash) != hash)
+    {
+        let existing_hash = base64::engine::general_purpose::STANDARD.encode(existing.hash);
+        return Ok(Response::from_status(409)
+            .with_header("x-existing-hash", format!("blake3-{existing_hash}"))
+            .with_body_text_plain(&format!(
+                "id collision, https://{host}/p/{id} stores different content\n\
+                 existing: blake3-{existing_hash}\n\
+                 uploaded: blake3-{}\n",
+                base64::engine::general_purpose::STANDARD.encode(hash.as_bytes())
+            )));
+    }
+    let is_duplicate = existing.is_some();
 
-        let meta = types::FileMetadata::new(hash.into(), mime);
+    // Same content stored as another kind, or by an older upload with options, is never reused
+    let kind = is_shorten.then(|| "redirect".to_string());
+    if existing
+        .as_ref()
+        .is_some_and(|m| m.kind != kind || has_upload_options(m))
+    {
+        return Ok(Response::from_status(409).with_body_text_plain(&format!(
+            "https://{host}/p/{id} stores the same content with other options\n\
+             upload with ?random for a new paste\n"
+        )));
+    }
 
-        kv.build_insert()
-            .metadata(&serde_json::to_string(&meta).unwrap())
-            .time_to_live(config::KV_TTL)
-            .execute(key, body)?;
-        track_upload(&kv, id, filename.unwrap_or("undefined"))?;
+    // Only create new pastes when the client insists on it
+    if is_duplicate && req.get_header_str(header::IF_NONE_MATCH).map(str::trim) == Some("*") {
+        return Ok(Response::from_status(412)
+            .with_body_text_plain(&format!("https://{host}/p/{id} already exists\n")));
     }
 
-    println!("put {key} in storage");
+    let mut manage_token = None;
+    if !is_duplicate {
+        let mime = if encrypted.is_some() {
+            // encrypted content is opaque
+            mime::APPLICATION_OCTET_STREAM.to_string()
+        } else if is_shorten {
+            "text/uri-list".to_string()
+        } else if sanitized {
+            "image/svg+xml".to_string()
+        } else {
+            detected
+        };
+
+        let mut meta = types::FileMetadata::new(hash.into(), mime, body.len());
+        meta.signed = signed_ttl.is_some();
+        meta.encrypted = encrypted;
+        meta.title = title;
+        meta.kind = kind;
+        meta.sanitized = sanitized;
+        meta.embed = embed;
+        meta.max_views = max_views;
+        meta.expires = expires
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
+        meta.unlisted = unlisted;
+        meta.filename = filename.and_then(sanitize_filename);
+
+        // Random management token, only returned to the uploader
+        let token = bs58::encode(rand::random::<[u8; 32]>()).into_string();
+        meta.manage = Some(blake3::hash(token.as_bytes()).into());
+        manage_token = Some(token);
+
+        store_paste(
+            &kv,
+            &host,
+            id,
+            (!is_random).then_some(&base),
+            body,
+            meta,
+            filename,
+        )?;
+    } else {
+        log!("{key} already in storage");
+    }
 
+    let query = match signed_ttl {
+        Some(ttl) => {
+            let exp = (SystemTime::now() + ttl)
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            format!("?exp={exp}&sig={}", sign_paste(id, exp)?)
+        },
+        None => String::new(),
+    };
     let url = format!(
-        "https://{host}/p/{id}{}",
+        "https://{host}/p/{id}{}{query}",
         filename.map(|v| "/".to_string() + v).unwrap_or_default()
     );
     let origin_url = format!(
-        "https://{host}/p/{id}#integrity=blake3-{}",
+        "https://{host}/p/{id}{query}#integrity=blake3-{}",
         base64::engine::general_purpose::STANDARD.encode(hash.as_bytes())
     );
 
-    // Respond with download URL
-    Ok(Response::from_body(url + "\n")
+    // Respond with download URL, and a comment line for duplicates after it, so scripts reading
+    // the first line still get the url
+    let mut body = url + "\n";
+    if is_duplicate {
+        body += "# duplicate of an existing upload, no new storage was used\n";
+    }
+    let mut res = Response::from_body(body)
         .with_content_type(mime::TEXT_PLAIN_UTF_8)
-        .with_header("x-origin-url", origin_url))
+        .with_header("x-origin-url", origin_url);
+    if is_duplicate {
+        // Content was already stored, no new storage was consumed
+        res.set_header("x-duplicate", "true");
+    }
+    if let Some(token) = manage_token {
+        res.set_header("x-manage-token", token);
+    }
+    Ok(res)
 }
 
-/// Get upload count from the metadata, or fallback to the number of metric lines.
+/// Store a new paste compressed and encrypted as configured, along with its id indexes and
+/// upload metrics. Only random ids are stored without a content index `base`. Fails with
+/// [`StorageFull`] when the stored paste doesn't fit in the storage left.
 #[inline(always)]
-fn get_upload_count(kv: &KVStore) -> usize {
-    kv.lookup(config::UPLOAD_METRICS_KEY)
-        .ok()
-        .map(|mut v| {
-            v.metadata()
-                // try and parse from metadata
-                .and_then(|m| String::from_utf8_lossy(&m).parse().ok())
-                // otherwise, count number of metric lines
-                .unwrap_or(v.take_body_bytes().lines().count())
-        })
-        .unwrap_or_default()
-}
+fn store_paste(
+    kv: &KVStore,
+    host: &str,
+    id: &str,
+    base: Option<&str>,
+    body: Vec<u8>,
+    mut meta: FileMetadata,
+    filename: Option<&str>,
+) -> Result<(), Error> {
+    // probe image dimensions from the header
+    meta.dims = infer::is_image(&body)
+        .then(|| imagesize::blob_size(&body).ok())
+        .flatten()
+        .and_then(|s| Some((s.width.try_into().ok()?, s.height.try_into().ok()?)));
 
-/// Append the key and a timestamp to the metrics
-#[inline(always)]
-fn track_upload(kv: &KVStore, id: &str, file: &str) -> Result<(), Error> {
-    let new_count = get_upload_count(kv) + 1;
+    // Text typed without a charset (ie from the filename) is labeled, so browsers don't guess
+    if meta.mime().starts_with("text/") && !meta.mime().contains("charset=") {
+        if let Some(charset) = detect_charset(&body) {
+            meta.mime = Cow::from(format!("{}; charset={charset}", meta.mime()));
+        }
+    }
+
+    // Store text compressed, served as is to clients accepting gzip
+    let encoded = if config::COMPRESS_TEXT && meta.mime().starts_with("text/") {
+        encode_content(&body)
+    } else {
+        None
+    };
+    let body = match encoded {
+        Some(encoded) => {
+            meta.encoding = Some("gzip".into());
+            meta.encoded_size = Some(encoded.len());
+            encoded
+        },
+        None => body,
+    };
+
+    // Content is hashed before encryption, so ids and integrity still use the plaintext
+    let body = if config::ENCRYPT_AT_REST {
+        let nonce = rand::random::<[u8; 12]>();
+        meta.nonce = Some(nonce);
+        encrypt_content(&body, &nonce)?
+    } else {
+        body
+    };
+
+    // Pastes with an expiry time are kept until then, instead of the full ttl
+    let ttl = expires_at(&meta)
+        .and_then(|t| t.duration_since(SystemTime::now()).ok())
+        .map_or(config::KV_TTL, |ttl| ttl.min(config::KV_TTL));
+    // The quota is checked against the size actually stored, like the storage ledger
+    let size = body.len();
+    if !has_storage_for(size)? {
+        return Err(StorageFull.into());
+    }
+    let key = &tenant_key("file", host, id);
     kv.build_insert()
-        .mode(InsertMode::Append)
-        .metadata(&new_count.to_string())
-        .execute(
-            config::UPLOAD_METRICS_KEY,
-            format!(
-                "{:?} , {id} , {file}\n",
-                SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap_or_default()
-                    .as_millis()
-            ),
-        )?;
+        .metadata(&serde_json::to_string(&meta).unwrap())
+        .time_to_live(ttl)
+        .execute(key, body)?;
+    track_storage(size, ttl)?;
+    // views of a deleted paste with the same id are reset
+    if config::TRACK_VIEWS || meta.max_views.is_some() {
+        kv.delete(&tenant_key("views", host, id)).ok();
+    }
+    if let Some(base) = base {
+        kv.build_insert()
+            .time_to_live(config::KV_TTL)
+            .execute(&tenant_key("content", host, base), id.to_string())?;
+    }
+    if config::CASE_INSENSITIVE_IDS {
+        // the index is sharded by the lowercase id
+        let lower = id.to_lowercase();
+        kv_for(&lower)?
+            .build_insert()
+            .mode(InsertMode::Append)
+            .time_to_live(config::KV_TTL)
+            .execute(&tenant_key("lower", host, &lower), format!("{id}\n"))?;
+    }
+    log!("put {key} in storage");
+    if !meta.unlisted {
+        // the paste is already stored, so metrics failures are only logged
+        if let Err(e) = open_kv(config::KV_STORE).and_then(|metrics| {
+            track_upload(
+                &metrics,
+                host,
+                id,
+                filename.unwrap_or("undefined"),
+                meta.mime(),
+                meta.size.unwrap_or_default(),
+            )
+        }) {
+            log!("failed to track upload of {id}: {e}");
+        }
+        notify_upload(host, id, &meta);
+    }
     Ok(())
 }
 
-/// Handle a request to get a paste
+/// Check if a paste was uploaded with options limiting who can read it, or for how long
 #[inline(always)]
-fn handle_get(req: Request, nonce: usize) -> Result<Response, Error> {
-    let url = req.get_url();
-    let host = url.host().unwrap().to_string();
-    let mut segments = url.path_segments().unwrap();
-    match segments.next() {
-        // Usage page
-        Some("") => {
-            // For all other clients other than curl, wrap with html (ie, browsers)
-            if let Some(agent) = req.get_header_str("user-agent") {
-                if !(agent.starts_with("curl") || agent.starts_with("Wget")) {
-                    let usage = get_usage(&host, true)?;
-                    let html = format!(
-                        include_str!("templates/index.html"),
-                        host = host,
-                        body = htmlescape::encode_minimal(&String::from_utf8_lossy(
-                            &usage.into_bytes()
-                        )),
-                        nonce = nonce
-                    );
+fn has_upload_options(meta: &FileMetadata) -> bool {
+    meta.signed
+        || meta.expires.is_some()
+        || meta.max_views.is_some()
+        || meta.encrypted.is_some()
+        || meta.unlisted
+}
 
-                    return Ok(Response::new().with_body_text_html(&html));
-                }
-            }
+/// Get the mime type of an upload, from the client or detected from the (decoded) content
+#[inline(always)]
+fn upload_mime(content_type: Option<&str>, body: &[u8], filename: Option<&str>) -> String {
+    client_mime(content_type, body).unwrap_or_else(|| detect_mime(body, filename))
+}
 
-            let usage = get_usage(&host, false)?;
-            Ok(Response::new().with_body_text_plain(&usage))
-        },
+/// Detect the mime type of content from magic byte sequences, the filename, or utf-8 validity
+#[inline(always)]
+fn detect_mime(body: &[u8], filename: Option<&str>) -> String {
+    infer::get(body).map(|t| t.to_string()).unwrap_or_else(|| {
+        // try to detect from the (optionally) given filename
+        if let Some(mime) = filename.and_then(|f| mime_guess::from_path(f).into_iter().next()) {
+            mime.to_string()
+        } else if std::str::from_utf8(body).is_ok() {
+            // if it's valid utf-8
+            mime::TEXT_PLAIN_UTF_8.to_string()
+        } else {
+            // fallback to raw octet stream bytes
+            mime::APPLICATION_OCTET_STREAM.to_string()
+        }
+    })
+}
 
-        // Privacy policy page
-        Some("privacy") => {
-            const PRIVACY: &str = include_str!("static/privacy.txt");
+/// Parse a space or comma separated list of http origins, or `*` for any, allowed to embed a
+/// paste. Returns `None` if any origin is invalid.
+#[inline(always)]
+fn parse_embed_origins(value: &str) -> Option<String> {
+    if value.trim() == "*" {
+        return Some("*".to_string());
+    }
+    let origins = value
+        .split([' ', ','])
+        .filter(|v| !v.is_empty())
+        .map(|v| {
+            let url = fastly::http::Url::parse(v).ok()?;
+            let valid = matches!(url.scheme(), "http" | "https") && url.path() == "/";
+            valid.then(|| url.origin().ascii_serialization())
+        })
+        .collect::<Option<Vec<_>>>()?;
+    (!origins.is_empty() && origins.len() <= config::MAX_EMBED_ORIGINS).then(|| origins.join(" "))
+}
+
+/// Get the content type given by the client, unless it's generic or doesn't match the content,
+/// ie text types for binary content, or a different kind of detected file
+#[inline(always)]
+fn client_mime(content_type: Option<&str>, body: &[u8]) -> Option<String> {
+    const GENERIC: &[&str] = &[
+        "application/octet-stream",
+        "application/x-www-form-urlencoded",
+        "multipart/form-data",
+    ];
+
+    if !config::RESPECT_CONTENT_TYPE {
+        return None;
+    }
+    let mime: mime::Mime = content_type?.parse().ok()?;
+    if GENERIC.contains(&mime.essence_str())
+        || mime.type_() == mime::IMAGE && mime.subtype() == mime::SVG
+    {
+        // svgs are only stored with their type once sanitized
+        return None;
+    }
+    let is_text = mime.type_() == mime::TEXT
+        || matches!(mime.subtype().as_str(), "json" | "xml" | "javascript");
+    let plausible = match infer::get(body) {
+        _ if is_text => std::str::from_utf8(body).is_ok(),
+        Some(detected) => detected.mime_type().split('/').next() == Some(mime.type_().as_str()),
+        // ambiguous binary formats magic bytes don't cover
+        None => mime.type_() == mime::APPLICATION,
+    };
+    plausible.then(|| mime.to_string())
+}
+
+/// Get a url safe slug from the first non-empty line of text, ie `fix-the-build`, or `None` for
+/// binary content or lines without any letters or digits.
+#[inline(always)]
+fn slugify(body: &[u8]) -> Option<String> {
+    let line = std::str::from_utf8(body)
+        .ok()?
+        .lines()
+        .find(|l| !l.trim().is_empty())?;
+    let mut slug = String::new();
+    for c in line.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+        if slug.len() >= config::MAX_SLUG_SIZE {
+            break;
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+    (!slug.is_empty()).then(|| slug.to_string())
+}
+
+/// Get the filename of a client's `Content-Disposition` header, ie `attachment; filename="a.log"`,
+/// without any directories and percent encoded like a filename in the upload url.
+#[inline(always)]
+fn disposition_filename(value: &str) -> Option<String> {
+    let (mut plain, mut extended) = (None, None);
+    for param in value.split(';').skip(1) {
+        let Some((key, value)) = param.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"');
+        match key.trim().to_ascii_lowercase().as_str() {
+            "filename" => plain = Some(value.to_string()),
+            // rfc 5987 encoding, ie `UTF-8''a%20b.log`
+            "filename*" => {
+                let mut parts = value.splitn(3, '\'');
+                if let (Some(charset), Some(_), Some(encoded)) =
+                    (parts.next(), parts.next(), parts.next())
+                {
+                    if charset.eq_ignore_ascii_case("utf-8") {
+                        extended = urlencoding::decode(encoded).ok().map(|v| v.into_owned());
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+
+    // only the last path component, so names can't traverse directories
+    let name = extended.or(plain)?;
+    let name = name.rsplit(['/', '\\']).next()?.trim();
+    if matches!(name, "" | "." | "..") || name.chars().any(char::is_control) {
+        return None;
+    }
+    Some(urlencoding::encode(name).into_owned())
+}
+
+/// Get a filename safe for the content disposition header, or `None` if it's too long
+#[inline(always)]
+fn sanitize_filename(filename: &str) -> Option<String> {
+    (filename.len() <= config::MAX_FILENAME_SIZE)
+        .then(|| filename.replace(|c: char| c.is_control() || c == '"' || c == '\\', "_"))
+}
+
+/// Store each file in a tar or zip archive as its own paste, and respond with the url of an
+/// index paste linking to all of them.
+#[inline(always)]
+fn handle_unpack(req: &Request, archive: &[u8]) -> Result<Response, Error> {
+    let url = req.get_url();
+    let host = url.host_str().unwrap_or_default().to_string();
+    let files = match infer::get(archive).map(|t| t.mime_type()) {
+        Some("application/zip") => unzip(archive),
+        Some("application/x-tar") => untar(archive),
+        _ => {
+            return Ok(
+                Response::from_status(415).with_body_text_plain("expected a tar or zip archive")
+            );
+        },
+    };
+    let files = match files {
+        Ok(files) => files,
+        Err((status, msg)) => return Ok(Response::from_status(status).with_body_text_plain(msg)),
+    };
+    // One management token deletes any of the newly stored files
+    let token = bs58::encode(rand::random::<[u8; 32]>()).into_string();
+    let manage = blake3::hash(token.as_bytes()).into();
+    let unlisted = is_unlisted(req);
+    // Like single uploads, pastes with upload options always get a random id
+    let is_random = config::RANDOM_IDS || has_query_flag(req, "random") || unlisted;
+
+    let name = url
+        .path_segments()
+        .into_iter()
+        .flatten()
+        .next_back()
+        .filter(|v| !v.is_empty())
+        .unwrap_or("files");
+    let mut index = format!("# {}\n\n", escape_markdown(name));
+    for (name, body) in files {
+        let label = escape_markdown(&name);
+        if body.len() < config::MIN_CONTENT_SIZE {
+            index += &format!("- {label} (skipped, too small)\n");
+            continue;
+        }
+        let body = if config::STRIP_EXIF && infer::is_image(&body) {
+            strip_exif(body)
+        } else {
+            body
+        };
+        let sanitized = is_unsafe_svg(&body, &detect_mime(&body, Some(&name)));
+        let Some(body) = (if sanitized {
+            sanitize_svg(&body)
+        } else {
+            Some(body)
+        }) else {
+            index += &format!("- {label} (skipped, invalid svg)\n");
+            continue;
+        };
+        // Stored like names from upload urls, which are still percent encoded
+        let name = urlencoding::encode(&name);
+        match store_unpacked(&host, body, &name, sanitized, unlisted, is_random, manage) {
+            Ok(Some(id)) => index += &format!("- [{label}](https://{host}/p/{id}/{name})\n"),
+            Ok(None) => {
+                index += &format!("- {label} (skipped, id collision, unpack with ?random)\n")
+            },
+            Err(e) if e.is::<StorageFull>() => index += &format!("- {label} (skipped, {e})\n"),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let Some(id) = store_unpacked(
+        &host,
+        index.into_bytes(),
+        "index.md",
+        false,
+        unlisted,
+        is_random,
+        manage,
+    )?
+    else {
+        return Ok(Response::from_status(409).with_body_text_plain("id collision for the index"));
+    };
+    Ok(
+        Response::from_body(format!("https://{host}/p/{id}/index.md\n"))
+            .with_content_type(mime::TEXT_PLAIN_UTF_8)
+            .with_header("x-manage-token", token),
+    )
+}
+
+/// Store a file from an archive under a random id, or under its content id unless it's already
+/// stored. Returns `None` if different content, or the same content with upload options, is
+/// stored with the content id.
+#[inline(always)]
+fn store_unpacked(
+    host: &str,
+    body: Vec<u8>,
+    filename: &str,
+    sanitized: bool,
+    unlisted: bool,
+    is_random: bool,
+    manage: [u8; 32],
+) -> Result<Option<String>, Error> {
+    let hash = blake3::hash(&body);
+    let base = encode_id(hash.as_bytes());
+    let id = if is_random {
+        random_id(host)?
+    } else {
+        find_canonical_id(&kv_for(&base)?, host, &base, &hash)
+            .unwrap_or_else(|| base[..id_size().min(base.len())].to_string())
+    };
+    let kv = kv_for(&id)?;
+    let existing = kv
+        .lookup(&tenant_key("file", host, &id))
+        .ok()
+        .and_then(|v| v.metadata())
+        .and_then(|m| serde_json::from_slice::<FileMetadata>(&m).ok())
+        .filter(|m| m.deleted.is_none());
+    match existing {
+        Some(existing)
+            if blake3::Hash::from(existing.hash) != hash
+                || existing.kind.is_some()
+                || has_upload_options(&existing) =>
+        {
+            return Ok(None);
+        },
+        Some(_) => return Ok(Some(id)),
+        None => {},
+    }
+
+    let mime = if sanitized {
+        "image/svg+xml".to_string()
+    } else {
+        detect_mime(&body, Some(filename))
+    };
+    let mut meta = types::FileMetadata::new(hash.into(), mime, body.len());
+    meta.sanitized = sanitized;
+    meta.unlisted = unlisted;
+    meta.filename = sanitize_filename(filename);
+    meta.manage = Some(manage);
+    store_paste(
+        &kv,
+        host,
+        &id,
+        (!is_random).then_some(&base),
+        body,
+        meta,
+        Some(filename),
+    )?;
+    Ok(Some(id))
+}
+
+/// Get a zip archive of several pastes by their stored filenames. Pastes that can't be added
+/// are noted in a manifest instead.
+#[inline(always)]
+fn get_zip(host: &str, ids: &[String]) -> Result<Response, Error> {
+    use zip::write::SimpleFileOptions;
+
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let mut names = std::collections::HashSet::new();
+    let mut skipped = String::new();
+    let mut total = 0;
+    for id in ids {
+        let (content, meta) = match get_paste(host, id, false, None, None, false, false) {
+            // signed pastes are only served through their signed url
+            Ok((_, meta)) if meta.signed => {
+                skipped += &format!("{id}: requires a signed url\n");
+                continue;
+            },
+            Ok((content, meta)) => match record_view(host, id, &meta) {
+                Ok(_) => (Body::from(content).into_bytes(), meta),
+                Err(PasteError::Deleted) => {
+                    skipped += &format!("{id}: deleted\n");
+                    continue;
+                },
+                Err(_) => {
+                    skipped += &format!("{id}: unavailable\n");
+                    continue;
+                },
+            },
+            Err(PasteError::NotFound) => {
+                skipped += &format!("{id}: not found\n");
+                continue;
+            },
+            Err(_) => {
+                skipped += &format!("{id}: unavailable\n");
+                continue;
+            },
+        };
+        // only written pastes count towards the limit, so smaller ones after a skip still fit
+        if total + content.len() > config::MAX_UNPACK_SIZE {
+            skipped += &format!("{id}: archive too large\n");
+            continue;
+        }
+        total += content.len();
+
+        // Pastes with the same filename are prefixed with their id
+        let name = meta.filename.clone().unwrap_or_else(|| id.to_string());
+        let name = if names.insert(name.clone()) {
+            name
+        } else {
+            format!("{id}-{name}")
+        };
+        let method = if meta.mime.starts_with("text/") {
+            zip::CompressionMethod::Deflated
+        } else {
+            zip::CompressionMethod::Stored
+        };
+        zip.start_file(
+            name,
+            SimpleFileOptions::default().compression_method(method),
+        )?;
+        zip.write_all(&content)?;
+    }
+    if !skipped.is_empty() {
+        zip.start_file("MANIFEST.txt", SimpleFileOptions::default())?;
+        zip.write_all(format!("skipped pastes:\n{skipped}").as_bytes())?;
+    }
+
+    Ok(Response::from_body(zip.finish()?.into_inner())
+        .with_header(header::CONTENT_TYPE, "application/zip")
+        .with_header(
+            header::CONTENT_DISPOSITION,
+            r#"attachment; filename="pastes.zip""#,
+        ))
+}
+
+/// Files read from an archive by name, or the status and reason it can't be unpacked
+type Unpacked = Result<Vec<(String, Vec<u8>)>, (u16, &'static str)>;
+
+/// Read the regular files of a zip archive, by their base name
+#[inline(always)]
+fn unzip(archive: &[u8]) -> Unpacked {
+    const INVALID: (u16, &str) = (400, "invalid zip archive");
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(archive)).map_err(|_| INVALID)?;
+    let mut files = Vec::new();
+    let mut total = 0;
+    for i in 0..zip.len() {
+        let entry = zip.by_index(i).map_err(|_| INVALID)?;
+        if entry.is_file() {
+            let name = entry.name().to_string();
+            read_unpacked(&mut files, &mut total, &name, entry)?;
+        }
+    }
+    Ok(files)
+}
+
+/// Read the regular files of a tar archive, by their base name
+#[inline(always)]
+fn untar(archive: &[u8]) -> Unpacked {
+    const INVALID: (u16, &str) = (400, "invalid tar archive");
+    let mut tar = tar::Archive::new(archive);
+    let mut files = Vec::new();
+    let mut total = 0;
+    for entry in tar.entries().map_err(|_| INVALID)? {
+        let entry = entry.map_err(|_| INVALID)?;
+        if entry.header().entry_type() == tar::EntryType::Regular {
+            let name = entry
+                .path()
+                .map_err(|_| INVALID)?
+                .to_string_lossy()
+                .into_owned();
+            read_unpacked(&mut files, &mut total, &name, entry)?;
+        }
+    }
+    Ok(files)
+}
+
+/// Read a file from an archive, never decompressing past the total unpacked size limit
+#[inline(always)]
+fn read_unpacked(
+    files: &mut Vec<(String, Vec<u8>)>,
+    total: &mut usize,
+    path: &str,
+    entry: impl std::io::Read,
+) -> Result<(), (u16, &'static str)> {
+    // skip metadata added by macos archivers
+    let name = path.rsplit('/').next().unwrap_or_default();
+    if name.is_empty() || name.starts_with("._") || path.starts_with("__MACOSX/") {
+        return Ok(());
+    }
+    if files.len() == config::MAX_UNPACK_FILES {
+        return Err((413, "too many files in archive"));
+    }
+
+    let remaining = config::MAX_UNPACK_SIZE - *total;
+    let mut content = Vec::new();
+    entry
+        .take(remaining as u64 + 1)
+        .read_to_end(&mut content)
+        .map_err(|_| (400, "invalid archive"))?;
+    if content.len() > remaining {
+        return Err((413, "unpacked archive too large"));
+    }
+    *total += content.len();
+    files.push((name.to_string(), content));
+    Ok(())
+}
+
+/// Escape characters with meaning in markdown link text
+#[inline(always)]
+fn escape_markdown(text: &str) -> String {
+    text.chars()
+        .fold(String::with_capacity(text.len()), |mut out, c| {
+            if matches!(c, '\\' | '[' | ']' | '*' | '_' | '`' | '<' | '>' | '#') {
+                out.push('\\');
+            }
+            out.push(c);
+            out
+        })
+}
+
+/// Post a new upload to the webhook backend, if configured. The request is sent in the
+/// background without waiting for a response, so only failures to send are logged.
+#[inline(always)]
+fn notify_upload(host: &str, id: &str, meta: &FileMetadata) {
+    let Some(backend) = config::WEBHOOK_BACKEND else {
+        return;
+    };
+    let payload = json!({
+        "id": id,
+        "mime": meta.mime(),
+        "size": meta.size,
+        "host": host,
+    });
+    match Request::post(config::WEBHOOK_URL)
+        .with_body(payload.to_string())
+        .with_content_type(mime::APPLICATION_JSON)
+        .send_async(backend)
+    {
+        Ok(_) => log!("notifying webhook of {id}"),
+        Err(e) => log!("webhook failed for {id}: {e}"),
+    }
+}
+
+/// Configured id alphabet, where invalid alphabets fail at compile time
+const ID_ALPHABET: bs58::Alphabet = bs58::Alphabet::new_unwrap(config::ID_ALPHABET);
+
+/// Encode bytes with the configured id alphabet
+#[inline(always)]
+fn encode_id(bytes: &[u8]) -> String {
+    encode_with(&ID_ALPHABET, bytes)
+}
+
+/// Decode an id with the configured id alphabet
+#[inline(always)]
+fn decode_id(id: &str) -> Option<Vec<u8>> {
+    decode_with(&ID_ALPHABET, id)
+}
+
+/// Encode bytes as base58 with an alphabet
+#[inline(always)]
+fn encode_with(alphabet: &bs58::Alphabet, bytes: &[u8]) -> String {
+    bs58::encode(bytes).with_alphabet(alphabet).into_string()
+}
+
+/// Decode base58 with an alphabet, or none if it has characters outside of it
+#[inline(always)]
+fn decode_with(alphabet: &bs58::Alphabet, id: &str) -> Option<Vec<u8>> {
+    bs58::decode(id).with_alphabet(alphabet).into_vec().ok()
+}
+
+/// Generate a random id that isn't stored yet, retrying on the rare clash
+#[inline(always)]
+fn random_id(host: &str) -> Result<String, Error> {
+    for _ in 0..config::RANDOM_ID_ATTEMPTS {
+        let base = encode_id(&rand::random::<[u8; 32]>());
+        let id = &base[..id_size().min(base.len())];
+        if kv_for(id)?.lookup(&tenant_key("file", host, id)).is_err() {
+            return Ok(id.to_string());
+        }
+    }
+    Err(Error::msg("failed to generate a unique random id"))
+}
+
+/// Get the target of a link to shorten, if the body is a single http(s) url
+#[inline(always)]
+fn shorten_target(body: &[u8]) -> Option<String> {
+    let target = std::str::from_utf8(body).ok()?.trim();
+    if target.len() > config::MAX_URL_SIZE || target.contains(char::is_whitespace) {
+        return None;
+    }
+    let url = fastly::http::Url::parse(target).ok()?;
+    (matches!(url.scheme(), "http" | "https") && url.host().is_some()).then(|| url.to_string())
+}
+
+/// Find the id a content hash is already stored under, from the content index. Only trusted
+/// when the stored paste has the same full hash.
+#[inline(always)]
+fn find_canonical_id(kv: &KVStore, host: &str, base: &str, hash: &blake3::Hash) -> Option<String> {
+    let id = kv
+        .lookup(&tenant_key("content", host, base))
+        .ok()?
+        .take_body()
+        .into_string();
+    let meta = kv.lookup(&tenant_key("file", host, &id)).ok()?.metadata()?;
+    let meta: FileMetadata = serde_json::from_slice(&meta).ok()?;
+    (meta.deleted.is_none() && blake3::Hash::from(meta.hash) == *hash).then_some(id)
+}
+
+/// Get the cipher for content encrypted at rest, keyed from the service secret
+#[inline(always)]
+fn storage_cipher() -> Result<ChaCha20Poly1305, Error> {
+    let secret = SecretStore::open(config::SECRET_STORE)?
+        .get(config::STORAGE_SECRET)
+        .ok_or_else(|| Error::msg("storage secret to exist"))?;
+    let key = blake3::derive_key("0dd.sh 2025-01-01 content at rest", &secret.plaintext());
+    Ok(ChaCha20Poly1305::new(&key.into()))
+}
+
+/// Encrypt content for storage with the given nonce
+#[inline(always)]
+fn encrypt_content(body: &[u8], nonce: &[u8; 12]) -> Result<Vec<u8>, Error> {
+    storage_cipher()?
+        .encrypt(nonce.into(), body)
+        .map_err(|_| Error::msg("failed to encrypt content"))
+}
+
+/// Decrypt stored content with the given nonce
+#[inline(always)]
+fn decrypt_content(body: &[u8], nonce: &[u8; 12]) -> Result<Vec<u8>, Error> {
+    storage_cipher()?
+        .decrypt(nonce.into(), body)
+        .map_err(|_| Error::msg("failed to decrypt content"))
+}
+
+/// Compress text content for storage, returning the encoding if it's smaller than the original
+#[inline(always)]
+fn encode_content(body: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body).ok()?;
+    let encoded = encoder.finish().ok()?;
+    (encoded.len() < body.len()).then_some(encoded)
+}
+
+/// Decode stored content with the given encoding
+#[inline(always)]
+fn decode_content(body: Body, encoding: Option<&str>) -> Result<Vec<u8>, Error> {
+    match encoding {
+        None => Ok(body.into_bytes()),
+        Some("gzip") => {
+            let mut content = Vec::new();
+            GzDecoder::new(body).read_to_end(&mut content)?;
+            Ok(content)
+        },
+        Some("br") => {
+            let mut content = Vec::new();
+            brotli::Decompressor::new(body, 4096).read_to_end(&mut content)?;
+            Ok(content)
+        },
+        Some(e) => Err(Error::msg(format!("unsupported stored encoding {e}"))),
+    }
+}
+
+/// Decode an upload with its lowercase content encoding, or the status and reason it can't be
+#[inline(always)]
+fn decode_upload(body: Vec<u8>, encoding: Option<&str>) -> Result<Vec<u8>, (u16, String)> {
+    match encoding {
+        None | Some("identity") => Ok(body),
+        Some("gzip" | "x-gzip") => {
+            // bounded to one byte over the maximum, so decompression bombs stop early
+            let mut decoded = Vec::new();
+            let limit = config::MAX_CONTENT_SIZE as u64 + 1;
+            if GzDecoder::new(body.as_slice())
+                .take(limit)
+                .read_to_end(&mut decoded)
+                .is_err()
+            {
+                return Err((400, "invalid gzip body".to_string()));
+            }
+            if decoded.len() > config::MAX_CONTENT_SIZE {
+                return Err((413, "content too large".to_string()));
+            }
+            Ok(decoded)
+        },
+        Some(encoding) => Err((415, format!("unsupported content encoding {encoding}"))),
+    }
+}
+
+/// Remove a utf-8 byte order mark from text, leaving binary content untouched
+#[inline(always)]
+fn strip_bom(body: Vec<u8>) -> Vec<u8> {
+    match body.strip_prefix(b"\xEF\xBB\xBF") {
+        Some(text) if std::str::from_utf8(text).is_ok() => text.to_vec(),
+        _ => body,
+    }
+}
+
+/// Convert crlf and cr line endings to lf
+#[inline(always)]
+fn normalize_line_endings(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// Check if a client's accept-encoding header accepts a content encoding
+#[inline(always)]
+fn accepts_encoding(accept_encoding: Option<&str>, encoding: &str) -> bool {
+    accept_encoding.unwrap_or_default().split(',').any(|part| {
+        let mut params = part.trim().split(';');
+        params
+            .next()
+            .is_some_and(|e| e.trim().eq_ignore_ascii_case(encoding))
+            && !params.any(|p| matches!(p.trim(), "q=0" | "q=0.0" | "q=0.00" | "q=0.000"))
+    })
+}
+
+/// Strip exif metadata from supported images, leaving any other content untouched.
+#[inline(always)]
+fn strip_exif(body: Vec<u8>) -> Vec<u8> {
+    let bytes = Bytes::from(body);
+    match DynImage::from_bytes(bytes.clone()) {
+        Ok(Some(mut image)) if image.exif().is_some() => {
+            image.set_exif(None);
+            image.encoder().bytes().into()
+        },
+        // unsupported formats (ie, tiff) or images without any exif data
+        _ => bytes.into(),
+    }
+}
+
+/// Check if content looks like an svg document
+#[inline(always)]
+fn is_svg(body: &[u8]) -> bool {
+    let head = &body[..body.len().min(4096)];
+    head.trim_ascii_start().starts_with(b"<")
+        && head.windows(4).any(|w| w.eq_ignore_ascii_case(b"<svg"))
+}
+
+/// Check if an upload has to be sanitized before storing, because it looks like an svg or would
+/// be stored with the svg type, ie from its filename
+#[inline(always)]
+fn is_unsafe_svg(body: &[u8], mime: &str) -> bool {
+    config::SANITIZE_SVG && (mime.starts_with("image/svg") || is_svg(body))
+}
+
+/// Remove scripts, event handlers, and external references from an svg, or `None` if it isn't
+/// well formed xml.
+#[inline(always)]
+fn sanitize_svg(body: &[u8]) -> Option<Vec<u8>> {
+    use quick_xml::events::{BytesStart, Event};
+
+    const BLOCKED: &[&str] = &["script", "foreignobject", "iframe", "embed", "object"];
+    // Animations can rewrite links after sanitizing, ie `<set attributeName="href" to="...">`
+    let is_animated_link = |e: &BytesStart| {
+        let name = e.local_name().as_ref().to_ascii_lowercase();
+        matches!(name.as_slice(), b"set" | b"animate")
+            && e.attributes().flatten().any(|attr| {
+                let name = attr.key.local_name().as_ref().to_ascii_lowercase();
+                let target = attr.value.rsplit(|&b| b == b':').next().unwrap_or_default();
+                name == b"attributename"
+                    && matches!(&*target.to_ascii_lowercase(), b"href" | b"src")
+            })
+    };
+    let is_blocked = |e: &BytesStart| {
+        let name = e.local_name();
+        BLOCKED
+            .iter()
+            .any(|b| b.as_bytes().eq_ignore_ascii_case(name.as_ref()))
+            || is_animated_link(e)
+    };
+    // Keep only attributes that can't run scripts or load anything outside the document. Names
+    // keep their prefix (ie `xlink:href`), and are classified by their local name.
+    let clean = |e: &BytesStart| -> Option<BytesStart<'static>> {
+        let mut clean = BytesStart::new(String::from_utf8_lossy(e.name().as_ref()).into_owned());
+        for attr in e.attributes() {
+            let attr = attr.ok()?;
+            let name = attr.key.local_name();
+            let key = name.as_ref();
+            let value = attr
+                .unescape_value()
+                .ok()?
+                .to_ascii_lowercase()
+                .replace(' ', "");
+            let is_handler = key.len() > 2 && key[..2].eq_ignore_ascii_case(b"on");
+            // raster data urls are the only non fragment links kept
+            let is_raster =
+                value.starts_with("data:image/") && !value.starts_with("data:image/svg");
+            let is_external_link =
+                matches!(key, b"href" | b"src") && !value.starts_with('#') && !is_raster;
+            if !is_handler && !is_external_link && !is_unsafe_css(&value) {
+                clean.push_attribute((attr.key.as_ref(), attr.value.as_ref()));
+            }
+        }
+        Some(clean)
+    };
+
+    let mut reader = quick_xml::Reader::from_reader(body);
+    let mut writer = quick_xml::Writer::new(Vec::with_capacity(body.len()));
+    // depth within a blocked element, all of which is dropped
+    let mut skip = 0usize;
+    // stylesheets can load external resources, so their rules are checked like style attributes
+    let mut in_style = false;
+    loop {
+        match reader.read_event().ok()? {
+            Event::Eof => break,
+            Event::Start(e) if skip > 0 || is_blocked(&e) => skip += 1,
+            Event::End(_) if skip > 0 => skip -= 1,
+            _ if skip > 0 => {},
+            Event::Empty(e) if is_blocked(&e) => {},
+            // doctypes can declare entities, and instructions can load stylesheets
+            Event::DocType(_) | Event::PI(_) => {},
+            Event::Text(text) if in_style => {
+                if text.unescape().is_ok_and(|css| !is_unsafe_css(&css)) {
+                    writer.write_event(Event::Text(text)).ok()?;
+                }
+            },
+            Event::CData(css) if in_style => {
+                if !is_unsafe_css(&String::from_utf8_lossy(&css)) {
+                    writer.write_event(Event::CData(css)).ok()?;
+                }
+            },
+            Event::Start(e) => {
+                in_style = e.local_name().as_ref().eq_ignore_ascii_case(b"style");
+                writer.write_event(Event::Start(clean(&e)?)).ok()?
+            },
+            Event::End(e) if in_style => {
+                in_style = false;
+                writer.write_event(Event::End(e)).ok()?
+            },
+            Event::Empty(e) => writer.write_event(Event::Empty(clean(&e)?)).ok()?,
+            e => writer.write_event(e).ok()?,
+        }
+    }
+    (skip == 0).then(|| writer.into_inner())
+}
+
+/// Check if css could load anything outside the document or run scripts. Escapes are rejected
+/// outright, since they could spell out either.
+#[inline(always)]
+fn is_unsafe_css(css: &str) -> bool {
+    let css = css.to_ascii_lowercase().replace(char::is_whitespace, "");
+    css.contains("@import")
+        || css.contains('\\')
+        || css.contains("script:")
+        || css
+            .split("url(")
+            .skip(1)
+            .any(|url| !url.trim_start_matches(['\'', '"']).starts_with('#'))
+}
+
+/// Get the upload ID length, from the config store override or the default constant. The store
+/// is only read once per request.
+#[inline(always)]
+fn id_size() -> usize {
+    static ID_SIZE: OnceLock<usize> = OnceLock::new();
+    *ID_SIZE.get_or_init(|| {
+        ConfigStore::try_open(config::CONFIG_STORE)
+            .ok()
+            .and_then(|store| store.try_get(config::ID_SIZE_KEY).ok().flatten())
+            .and_then(|v| match v.trim().parse::<usize>() {
+                Ok(size) => {
+                    let size = size.clamp(1, 64);
+                    log!(
+                        "using id size {size} from the {} override",
+                        config::ID_SIZE_KEY
+                    );
+                    Some(size)
+                },
+                Err(e) => {
+                    log!("invalid {} override {v:?}: {e}", config::ID_SIZE_KEY);
+                    None
+                },
+            })
+            .unwrap_or(config::ID_SIZE)
+    })
+}
+
+/// Check if a request is from a browser, rather than a command line client like curl or wget
+#[inline(always)]
+fn is_browser(req: &Request) -> bool {
+    req.get_header_str("user-agent")
+        .is_some_and(|agent| !(agent.starts_with("curl") || agent.starts_with("Wget")))
+}
+
+/// Get the color scheme override from the query or cookie, or empty to follow the browser. Pages
+/// using it vary on the cookie.
+#[inline(always)]
+fn get_theme(req: &Request) -> &'static str {
+    let cookie = req.get_header_str(header::COOKIE).and_then(|cookies| {
+        cookies
+            .split(';')
+            .find_map(|c| c.trim().strip_prefix("theme="))
+    });
+    match req.get_query_parameter("theme").or(cookie) {
+        Some("dark") => "dark",
+        Some("light") => "light",
+        _ => "",
+    }
+}
+
+/// Check if a query flag (ie, `?md`) is present, with or without a value
+#[inline(always)]
+fn has_query_flag(req: &Request, flag: &str) -> bool {
+    req.get_url().query_pairs().any(|(k, _)| k == flag)
+}
+
+/// Check if an upload is excluded from the metrics and listings, via `?unlisted` or `x-unlisted`
+#[inline(always)]
+fn is_unlisted(req: &Request) -> bool {
+    has_query_flag(req, "unlisted")
+        || req
+            .get_header_str("x-unlisted")
+            .is_some_and(|v| matches!(v.trim(), "1" | "true"))
+}
+
+/// Get the key for signing paste urls, derived from the service secret
+#[inline(always)]
+fn signing_key() -> Result<[u8; 32], Error> {
+    let secret = SecretStore::open(config::SECRET_STORE)?
+        .get(config::SIGNING_SECRET)
+        .ok_or_else(|| Error::msg("signing secret to exist"))?;
+    Ok(blake3::derive_key(
+        "0dd.sh 2024-10-01 signed paste urls",
+        &secret.plaintext(),
+    ))
+}
+
+/// Sign a paste id and expiry time, using keyed blake3 as the mac
+#[inline(always)]
+fn sign_paste(id: &str, exp: u64) -> Result<String, Error> {
+    let mac = blake3::keyed_hash(&signing_key()?, format!("{id}|{exp}").as_bytes());
+    Ok(bs58::encode(mac.as_bytes()).into_string())
+}
+
+/// Verify the signed url parameters for a paste request. Signatures are always checked when
+/// given, and required for pastes uploaded with `?signed`.
+#[inline(always)]
+fn verify_signature(req: &Request, id: &str, meta: &FileMetadata) -> Result<(), PasteError> {
+    let exp = req.get_query_parameter("exp");
+    let sig = req.get_query_parameter("sig");
+    if !meta.signed && exp.is_none() && sig.is_none() {
+        return Ok(());
+    }
+
+    let (Some(exp), Some(sig)) = (exp.and_then(|v| v.parse::<u64>().ok()), sig) else {
+        return Err(PasteError::InvalidSignature);
+    };
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if exp < now {
+        return Err(PasteError::InvalidSignature);
+    }
+
+    let expected = blake3::keyed_hash(&signing_key()?, format!("{id}|{exp}").as_bytes());
+    let mut bytes = [0; 32];
+    match bs58::decode(sig).onto(&mut bytes) {
+        // comparing blake3 hashes is constant time
+        Ok(32) if blake3::Hash::from(bytes) == expected => Ok(()),
+        _ => Err(PasteError::InvalidSignature),
+    }
+}
+
+/// Open a key-value store, failing with [`MissingStore`] when it isn't linked to the service
+#[inline(always)]
+fn open_kv(name: &'static str) -> Result<KVStore, Error> {
+    KVStore::open(name)?.ok_or_else(|| MissingStore(name).into())
+}
+
+/// Open the key-value store shard for a paste
+#[inline(always)]
+fn kv_for(id: &str) -> Result<KVStore, Error> {
+    open_kv(shard_for(id))
+}
+
+/// Get the name of the key-value store shard for a paste
+#[inline(always)]
+fn shard_for(id: &str) -> &'static str {
+    shard_in(config::KV_SHARDS, id)
+}
+
+/// Select a shard for an id by its hash, since the first characters of base58 ids are skewed
+#[inline(always)]
+fn shard_in<'a>(shards: &[&'a str], id: &str) -> &'a str {
+    let hash = blake3::hash(id.as_bytes());
+    let n = u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap());
+    shards[(n % shards.len() as u64) as usize]
+}
+
+/// Namespace a storage key with the host when multi-tenant, ie `file_{host}_{id}`
+#[inline(always)]
+fn tenant_key(prefix: &str, host: &str, id: &str) -> String {
+    tenant_key_with(config::MULTI_TENANT, prefix, host, id)
+}
+
+/// Format a storage key, namespaced with the host if multi-tenant
+#[inline(always)]
+fn tenant_key_with(multi_tenant: bool, prefix: &str, host: &str, id: &str) -> String {
+    if multi_tenant {
+        format!("{prefix}_{host}_{id}")
+    } else {
+        format!("{prefix}_{id}")
+    }
+}
+
+/// Get the upload metrics key, namespaced with the host when multi-tenant
+#[inline(always)]
+fn metrics_key(host: &str) -> Cow<'static, str> {
+    if config::MULTI_TENANT {
+        format!("{}_{host}", config::UPLOAD_METRICS_KEY).into()
+    } else {
+        config::UPLOAD_METRICS_KEY.into()
+    }
+}
+
+/// Get the upload mime metrics key, namespaced with the host when multi-tenant
+#[inline(always)]
+fn mime_metrics_key(host: &str) -> Cow<'static, str> {
+    if config::MULTI_TENANT {
+        format!("{}_{host}", config::MIME_METRICS_KEY).into()
+    } else {
+        config::MIME_METRICS_KEY.into()
+    }
+}
+
+/// Get the upload size metrics key, namespaced with the host when multi-tenant
+#[inline(always)]
+fn size_metrics_key(host: &str) -> Cow<'static, str> {
+    if config::MULTI_TENANT {
+        format!("{}_{host}", config::SIZE_METRICS_KEY).into()
+    } else {
+        config::SIZE_METRICS_KEY.into()
+    }
+}
+
+/// Get the number of uploads in each size bucket, smallest first
+#[inline(always)]
+fn get_size_counts(kv: &KVStore, host: &str) -> serde_json::Map<String, serde_json::Value> {
+    let counts = kv
+        .lookup(&size_metrics_key(host))
+        .map(|mut v| parse_counts(&v.take_body_bytes()))
+        .unwrap_or_default();
+    config::SIZE_BUCKETS
+        .iter()
+        .map(|bucket| {
+            let count = counts.get(&bucket.to_string()).copied().unwrap_or_default();
+            (
+                format!("<= {}", humanize_bytes_binary!(*bucket)),
+                count.into(),
+            )
+        })
+        .collect()
+}
+
+/// Get the number of uploads of each mime type, most common first
+#[inline(always)]
+fn get_mime_counts(kv: &KVStore, host: &str) -> serde_json::Map<String, serde_json::Value> {
+    let counts = kv
+        .lookup(&mime_metrics_key(host))
+        .map(|mut v| parse_counts(&v.take_body_bytes()))
+        .unwrap_or_default();
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    counts
+        .into_iter()
+        .map(|(mime, count)| (mime, count.into()))
+        .collect()
+}
+
+/// Parse a json object of counts by name, or count the names of older metrics, one per line
+#[inline(always)]
+fn parse_counts(body: &[u8]) -> std::collections::BTreeMap<String, u64> {
+    serde_json::from_slice(body).unwrap_or_else(|_| {
+        let mut counts = std::collections::BTreeMap::new();
+        for name in String::from_utf8_lossy(body).lines() {
+            *counts.entry(name.to_string()).or_default() += 1;
+        }
+        counts
+    })
+}
+
+/// Increment a count in the json object of counts at a key, counting new names past a maximum as
+/// `other`. Writes only replace the version they read, and are retried if another upload changed
+/// it in between, so the value never grows past the counted names.
+#[inline(always)]
+fn increment_count(kv: &KVStore, key: &str, name: &str, max_names: usize) -> Result<(), Error> {
+    for _ in 0..config::METRICS_RETRIES {
+        let (mut counts, generation) = match kv.lookup(key) {
+            Ok(mut v) => (parse_counts(&v.take_body_bytes()), Some(v.generation())),
+            Err(KVStoreError::ItemNotFound) => Default::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let name = if counts.contains_key(name) || counts.len() < max_names {
+            name
+        } else {
+            "other"
+        };
+        *counts.entry(name.to_string()).or_default() += 1;
+        let insert = match generation {
+            Some(generation) => kv.build_insert().if_generation_match(generation),
+            None => kv.build_insert().mode(InsertMode::Add),
+        };
+        match insert.execute(key, serde_json::to_string(&counts)?) {
+            Err(KVStoreError::ItemPreconditionFailed) => continue,
+            res => return Ok(res?),
+        }
+    }
+    Err(Error::msg(format!("{key} changed concurrently too often")))
+}
+
+/// Get the utc date of a time, ie `2026-12-31`
+#[inline(always)]
+fn utc_date(time: SystemTime) -> String {
+    humantime::format_rfc3339(time).to_string()[..10].to_string()
+}
+
+/// Get the number of uploads on each of the last days, oldest first
+#[inline(always)]
+fn get_daily_counts(kv: &KVStore, host: &str) -> serde_json::Map<String, serde_json::Value> {
+    (0..config::DAILY_METRICS_DAYS)
+        .rev()
+        .map(|days| {
+            let date = utc_date(SystemTime::now() - Duration::from_secs(days * 86400));
+            let key = tenant_key(config::DAILY_METRICS_KEY, host, &date.replace('-', ""));
+            let count = kv
+                .lookup(&key)
+                .map(|mut v| v.take_body_bytes().len())
+                .unwrap_or_default();
+            (date, count.into())
+        })
+        .collect()
+}
+
+/// Get upload count from the metadata, or fallback to the number of metric lines.
+#[inline(always)]
+fn get_upload_count(kv: &KVStore, host: &str) -> usize {
+    kv.lookup(&metrics_key(host))
+        .ok()
+        .map(|mut v| {
+            v.metadata()
+                // try and parse from metadata
+                .and_then(|m| String::from_utf8_lossy(&m).parse().ok())
+                // otherwise, count number of metric lines
+                .unwrap_or(v.take_body_bytes().lines().count())
+        })
+        .unwrap_or_default()
+}
+
+/// Append the key and a timestamp to the metrics, count the upload by mime type and size bucket,
+/// and append a marker to the uploads of the day
+#[inline(always)]
+fn track_upload(
+    kv: &KVStore,
+    host: &str,
+    id: &str,
+    file: &str,
+    mime: &str,
+    size: usize,
+) -> Result<(), Error> {
+    let essence = mime.split(';').next().unwrap_or_default().trim();
+    increment_count(
+        kv,
+        &mime_metrics_key(host),
+        essence,
+        config::MAX_METRICS_MIMES,
+    )?;
+    let bucket = config::SIZE_BUCKETS
+        .iter()
+        .find(|bucket| size <= **bucket)
+        .or(config::SIZE_BUCKETS.last())
+        .copied()
+        .unwrap_or_default();
+    increment_count(
+        kv,
+        &size_metrics_key(host),
+        &bucket.to_string(),
+        config::SIZE_BUCKETS.len(),
+    )?;
+    // counted by markers like views, kept until they're no longer reported
+    let date = utc_date(SystemTime::now()).replace('-', "");
+    kv.build_insert()
+        .mode(InsertMode::Append)
+        .time_to_live(Duration::from_secs(
+            (config::DAILY_METRICS_DAYS + 1) * 86400,
+        ))
+        .execute(&tenant_key(config::DAILY_METRICS_KEY, host, &date), ".")?;
+
+    let new_count = get_upload_count(kv, host) + 1;
+    kv.build_insert()
+        .mode(InsertMode::Append)
+        .metadata(&new_count.to_string())
+        .execute(
+            &metrics_key(host),
+            format!(
+                "{:?} , {id} , {file}\n",
+                SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis()
+            ),
+        )?;
+    Ok(())
+}
+
+/// Get the approximate total bytes stored, from the storage ledger metadata. Pastes are counted
+/// until their ttl, even if deleted before, and expired pastes until the ledger is reconciled.
+#[inline(always)]
+fn get_stored_bytes(kv: &KVStore) -> u64 {
+    kv.lookup(config::STORAGE_LEDGER_KEY)
+        .ok()
+        .and_then(|v| v.metadata())
+        .and_then(|m| String::from_utf8_lossy(&m).parse().ok())
+        .unwrap_or_default()
+}
+
+/// Check if there's room for an upload within the storage quota, if any
+#[inline(always)]
+fn has_storage_for(size: usize) -> Result<bool, Error> {
+    let Some(max) = config::MAX_TOTAL_BYTES else {
+        return Ok(true);
+    };
+    let kv = open_kv(config::KV_STORE)?;
+    Ok(get_stored_bytes(&kv) + size as u64 <= max)
+}
+
+/// Append the size and expiry time of a stored paste to the storage ledger, occasionally
+/// reconciling the total by removing pastes that have expired since.
+#[inline(always)]
+fn track_storage(size: usize, ttl: Duration) -> Result<(), Error> {
+    let kv = open_kv(config::KV_STORE)?;
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let line = format!("{} {size}\n", now + ttl.as_secs());
+
+    if rand::Rng::gen_ratio(&mut rand::thread_rng(), 1, config::STORAGE_RECONCILE_RATE) {
+        let ledger = kv
+            .lookup(config::STORAGE_LEDGER_KEY)
+            .map(|mut v| v.take_body_bytes())
+            .unwrap_or_default();
+        let mut total = size as u64;
+        let mut live = String::new();
+        for entry in String::from_utf8_lossy(&ledger).lines() {
+            let Some((expiry, bytes)) = entry.split_once(' ') else {
+                continue;
+            };
+            if let (Ok(expiry), Ok(bytes)) = (expiry.parse::<u64>(), bytes.parse::<u64>()) {
+                if expiry > now {
+                    total += bytes;
+                    live += entry;
+                    live.push('\n');
+                }
+            }
+        }
+        live += &line;
+        kv.build_insert()
+            .metadata(&total.to_string())
+            .execute(config::STORAGE_LEDGER_KEY, live)?;
+        log!("reconciled storage ledger, {total} bytes stored");
+    } else {
+        let total = get_stored_bytes(&kv) + size as u64;
+        kv.build_insert()
+            .mode(InsertMode::Append)
+            .metadata(&total.to_string())
+            .execute(config::STORAGE_LEDGER_KEY, line)?;
+    }
+    Ok(())
+}
+
+/// Handle an operator request, listing recent uploads or deleting a paste
+#[inline(always)]
+fn handle_admin(req: Request) -> Result<Response, Error> {
+    if !is_admin(&req)? {
+        return Ok(Response::from_status(401)
+            .with_header(header::WWW_AUTHENTICATE, "Bearer")
+            .with_body_text_plain("unauthorized"));
+    }
+
+    let url = req.get_url();
+    let host = url.host_str().unwrap_or_default().to_string();
+    let mut segments = url.path_segments().into_iter().flatten().skip(1);
+    match (
+        req.get_method(),
+        segments.next().filter(|v| !v.is_empty()),
+        segments.next(),
+    ) {
+        // List the most recent uploads, newest first
+        (&Method::GET, None, None) => {
+            let kv = open_kv(config::KV_STORE)?;
+            let metrics = kv
+                .lookup(&metrics_key(&host))
+                .map(|mut v| v.take_body_bytes())
+                .unwrap_or_default();
+            let recent = String::from_utf8_lossy(&metrics)
+                .lines()
+                .rev()
+                .take(config::ADMIN_LIST_SIZE)
+                .fold(String::new(), |acc, line| acc + line + "\n");
+            Ok(Response::new().with_body_text_plain(&recent))
+        },
+
+        // Delete a paste from storage and purge it from the cache
+        (&Method::DELETE, Some(id), None) => {
+            let key = tenant_key("file", &host, id);
+            let kv = kv_for(id)?;
+            let Some(meta) = kv.lookup(&key).ok().and_then(|v| v.metadata()) else {
+                return Ok(
+                    Response::from_status(404).with_body_text_plain(&format!("{id} not found"))
+                );
+            };
+            let meta: FileMetadata = serde_json::from_slice(&meta).expect("corrupted metadata");
+            delete_paste(&kv, &host, id, &meta)?;
+            log!("admin deleted {key}");
+            Ok(Response::new().with_body_text_plain(&format!("deleted {id}\n")))
+        },
+
+        _ => Ok(Response::from_status(404).with_body_text_plain("invalid request")),
+    }
+}
+
+/// Handle an operator request to purge a paste from the cache, and delete it from storage with
+/// `?delete`
+#[inline(always)]
+fn handle_purge(req: Request) -> Result<Response, Error> {
+    if !is_admin(&req)? {
+        return Ok(Response::from_status(401)
+            .with_header(header::WWW_AUTHENTICATE, "Bearer")
+            .with_body_text_plain("unauthorized"));
+    }
+
+    let url = req.get_url();
+    let host = url.host_str().unwrap_or_default().to_string();
+    let Some(id) = url
+        .path_segments()
+        .into_iter()
+        .flatten()
+        .nth(1)
+        .filter(|v| !v.is_empty())
+    else {
+        return Ok(Response::from_status(404).with_body_text_plain("expected paste id"));
+    };
+
+    if has_query_flag(&req, "delete") {
+        let key = tenant_key("file", &host, id);
+        let kv = kv_for(id)?;
+        if let Some(meta) = kv.lookup(&key).ok().and_then(|v| v.metadata()) {
+            let meta: FileMetadata = serde_json::from_slice(&meta).expect("corrupted metadata");
+            delete_paste(&kv, &host, id, &meta)?;
+            log!("admin deleted {key}");
+        }
+    }
+    fastly::http::purge::purge_surrogate_key(&surrogate_key(&host, id))?;
+    log!("admin purged {id}");
+    Ok(Response::from_status(204))
+}
+
+/// Check the bearer token of an operator request against the admin secret
+#[inline(always)]
+fn is_admin(req: &Request) -> Result<bool, Error> {
+    let Some(token) = req
+        .get_header_str(header::AUTHORIZATION)
+        .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        return Ok(false);
+    };
+    let Some(secret) = SecretStore::open(config::SECRET_STORE)?.get(config::ADMIN_SECRET) else {
+        return Ok(false);
+    };
+    // comparing blake3 hashes is constant time
+    Ok(blake3::hash(token.trim().as_bytes()) == blake3::hash(&secret.plaintext()))
+}
+
+/// Respond to a client that's reported too many pastes, with when to retry in seconds
+#[inline(always)]
+fn too_many_reports() -> Response {
+    // the penalty box doesn't expose the time left, so this is an upper bound
+    Response::from_status(429)
+        .with_header(
+            header::RETRY_AFTER,
+            config::REPORT_PENALTY_TTL.as_secs().to_string(),
+        )
+        .with_body_text_plain("too many reports")
+}
+
+/// Handle a post request (abuse reports)
+#[inline(always)]
+fn handle_post(mut req: Request) -> Result<Response, Error> {
+    let url = req.get_url().clone();
+    let mut segments = url.path_segments().into_iter().flatten();
+    let (Some("report"), Some(id), None) = (segments.next(), segments.next(), segments.next())
+    else {
+        return Ok(Response::from_status(404).with_body_text_plain("invalid request"));
+    };
+
+    // Rate limit reports per client ip
+    if let Some(ip) = req.get_client_ip_addr() {
+        if is_report_limited(&ip.to_string())? {
+            return Ok(too_many_reports());
+        }
+    }
+
+    let host = url.host_str().unwrap_or_default().to_string();
+    let kv = kv_for(id)?;
+    if kv.lookup(&tenant_key("file", &host, id)).is_err() {
+        return Ok(Response::from_status(404).with_body_text_plain(&format!("{id} not found")));
+    }
+
+    // Optional reason from the body, flattened to a single line
+    let mut body = req.take_body_bytes();
+    body.truncate(config::MAX_REPORT_SIZE);
+    let reason = String::from_utf8_lossy(&body).replace(['\r', '\n'], " ");
+
+    track_report(&kv, &host, id, reason.trim())?;
+    log!("reported {id}");
+
+    Ok(Response::from_status(202).with_body_text_plain(&format!("reported {id}\n")))
+}
+
+/// Check and increment the report rate for a client, adding them to the penalty box when over
+/// the limit.
+#[inline(always)]
+fn is_report_limited(ip: &str) -> Result<bool, Error> {
+    let penalty_box = Penaltybox::open(config::REPORT_PENALTY_BOX);
+    if penalty_box.has(ip)? {
+        return Ok(true);
+    }
+
+    let counter = RateCounter::open(config::REPORT_RATE_COUNTER);
+    counter.increment(ip, 1)?;
+    if counter.lookup_count(ip, CounterDuration::SixtySecs)? > config::REPORT_RATE_LIMIT {
+        penalty_box.add(ip, config::REPORT_PENALTY_TTL)?;
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
+/// Append a timestamp and reason to the reports for a paste, incrementing the report counter
+#[inline(always)]
+fn track_report(kv: &KVStore, host: &str, id: &str, reason: &str) -> Result<(), Error> {
+    let key = tenant_key("report", host, id);
+    let count = kv
+        .lookup(&key)
+        .ok()
+        .and_then(|v| v.metadata())
+        .and_then(|m| String::from_utf8_lossy(&m).parse::<usize>().ok())
+        .unwrap_or_default();
+    kv.build_insert()
+        .mode(InsertMode::Append)
+        .metadata(&(count + 1).to_string())
+        .execute(
+            &key,
+            format!(
+                "{:?} , {reason}\n",
+                SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis()
+            ),
+        )?;
+    Ok(())
+}
+
+/// Handle a delete request, authorized by the management token or the original content
+#[inline(always)]
+fn handle_delete(mut req: Request) -> Result<Response, Error> {
+    let url = req.get_url().clone();
+    let host = url.host_str().unwrap_or_default().to_string();
+    let mut segments = url.path_segments().into_iter().flatten();
+    let (Some("p"), Some(id)) = (segments.next(), segments.next()) else {
+        return Ok(Response::from_status(404).with_body_text_plain("invalid request"));
+    };
+
+    let key = tenant_key("file", &host, id);
+    let kv = kv_for(id)?;
+    let Some(meta) = kv.lookup(&key).ok().and_then(|v| v.metadata()) else {
+        return Ok(Response::from_status(404).with_body_text_plain(&format!("{id} not found")));
+    };
+    let meta: FileMetadata = serde_json::from_slice(&meta).expect("corrupted metadata");
+    if meta.deleted.is_some() {
+        return Ok(paste_error_response(id, PasteError::Deleted));
+    }
+
+    // comparing blake3 hashes is constant time
+    let token = req.get_header_str("x-manage-token").map(str::trim);
+    let by_token = matches!(
+        (token, meta.manage),
+        (Some(token), Some(expected)) if blake3::hash(token.as_bytes()) == blake3::Hash::from(expected)
+    );
+    let by_content = !by_token && {
+        let body = req.take_body_bytes();
+        !body.is_empty() && blake3::hash(&body) == blake3::Hash::from(meta.hash)
+    };
+    if !(by_token || by_content) {
+        return Ok(Response::from_status(403).with_body_text_plain("invalid token or content"));
+    }
+
+    delete_paste(&kv, &host, id, &meta)?;
+    log!("deleted {key}");
+    Ok(Response::new().with_body_text_plain(&format!("deleted {id}\n")))
+}
+
+/// Delete a paste from storage, leaving a tombstone if enabled, and purge it from the cache
+#[inline(always)]
+fn delete_paste(kv: &KVStore, host: &str, id: &str, meta: &FileMetadata) -> Result<(), Error> {
+    let key = &tenant_key("file", host, id);
+    if config::TOMBSTONES {
+        let mut tombstone = FileMetadata::new(meta.hash, meta.mime().to_string(), 0);
+        tombstone.size = None;
+        tombstone.deleted = tombstone.created;
+        kv.build_insert()
+            .metadata(&serde_json::to_string(&tombstone).unwrap())
+            .time_to_live(config::KV_TTL)
+            .execute(key, "")?;
+    } else {
+        kv.delete(key)?;
+    }
+    fastly::http::purge::purge_surrogate_key(&surrogate_key(host, id))?;
+    Ok(())
+}
+
+/// Record a download of a paste in a key next to it, returning the previous access time. The
+/// timestamp is only overwritten, never read and modified, and failures are only logged.
+#[inline(always)]
+fn touch_paste(host: &str, id: &str) -> Option<u64> {
+    if !config::TRACK_ACCESS {
+        return None;
+    }
+    let key = tenant_key("accessed", host, id);
+    let kv = kv_for(id).ok()?;
+    let previous = kv
+        .lookup(&key)
+        .ok()
+        .and_then(|mut v| String::from_utf8_lossy(&v.take_body_bytes()).parse().ok());
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let is_recent = previous.is_some_and(|p: u64| now < p + config::ACCESS_RESOLUTION.as_secs());
+    if !is_recent {
+        if let Err(e) = kv
+            .build_insert()
+            .time_to_live(config::KV_TTL)
+            .execute(&key, now.to_string())
+        {
+            log!("failed to record access of {id}: {e}");
+        }
+    }
+    previous
+}
+
+/// Count a download of a paste, and rank it among the popular pastes unless it's unlisted or
+/// limited to a number of views. Failures are only logged.
+#[inline(always)]
+fn count_view(host: &str, id: &str, meta: &FileMetadata) -> Option<u64> {
+    if !config::TRACK_VIEWS && meta.max_views.is_none() {
+        return None;
+    }
+    // Each view appends a marker, and the count is the number of markers. Appends are atomic, so
+    // concurrent views are never lost, but may all see the count including each other.
+    let key = tenant_key("views", host, id);
+    let kv = kv_for(id).ok()?;
+    if let Err(e) = kv
+        .build_insert()
+        .mode(InsertMode::Append)
+        .time_to_live(config::KV_TTL)
+        .execute(&key, ".")
+    {
+        log!("failed to count view of {id}: {e}");
+        return None;
+    }
+    let views = kv.lookup(&key).ok()?.take_body_bytes().len() as u64;
+
+    // pastes about to be deleted aren't worth discovering, and the ranking is only sampled so
+    // views aren't all written to one key
+    if config::TRACK_VIEWS
+        && !meta.unlisted
+        && meta.max_views.is_none()
+        && rand::Rng::gen_ratio(&mut rand::thread_rng(), 1, config::POPULAR_RANK_RATE)
+    {
+        if let Err(e) = rank_paste(host, id, meta, views) {
+            log!("failed to rank {id}: {e}");
+        }
+    }
+    Some(views)
+}
+
+/// Count a download of a paste, deleting pastes limited to a number of views after the last
+/// one. Every response with the content records a view, and views past the limit are refused.
+#[inline(always)]
+fn record_view(host: &str, id: &str, meta: &FileMetadata) -> Result<Option<u64>, PasteError> {
+    let views = count_view(host, id, meta);
+    let Some(max) = meta.max_views else {
+        return Ok(views);
+    };
+    // the limit can't be enforced without the count
+    let views = views.ok_or(PasteError::Unavailable)?;
+    // concurrent views can all see a count past the limit, so any of them deletes the paste
+    if views >= max {
+        delete_paste(&kv_for(id)?, host, id, meta)?;
+        log!("deleted {id} after {views} views");
+    }
+    if views > max {
+        return Err(PasteError::Deleted);
+    }
+    Ok(Some(views))
+}
+
+/// Get the number of downloads of a paste without counting one, ie for probes
+#[inline(always)]
+fn get_views(host: &str, id: &str, meta: &FileMetadata) -> Option<u64> {
+    if !config::TRACK_VIEWS && meta.max_views.is_none() {
+        return None;
+    }
+    match kv_for(id).ok()?.lookup(&tenant_key("views", host, id)) {
+        Ok(mut v) => Some(v.take_body_bytes().len() as u64),
+        Err(KVStoreError::ItemNotFound) => Some(0),
+        Err(_) => None,
+    }
+}
+
+/// Get the popular ranking key, namespaced with the host when multi-tenant
+#[inline(always)]
+fn popular_key(host: &str) -> Cow<'static, str> {
+    if config::MULTI_TENANT {
+        format!("{}_{host}", config::POPULAR_KEY).into()
+    } else {
+        config::POPULAR_KEY.into()
+    }
+}
+
+/// Get the most viewed pastes that haven't expired yet, most viewed first
+#[inline(always)]
+fn get_popular(kv: &KVStore, host: &str) -> Vec<PopularPaste> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    kv.lookup(&popular_key(host))
+        .ok()
+        .and_then(|mut v| serde_json::from_slice::<Vec<PopularPaste>>(&v.take_body_bytes()).ok())
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|p| p.expires > now)
+        .collect()
+}
+
+/// Update a paste's views in the popular ranking, only writing when the ranking changes
+#[inline(always)]
+fn rank_paste(host: &str, id: &str, meta: &FileMetadata, views: u64) -> Result<(), Error> {
+    let kv = open_kv(config::KV_STORE)?;
+    let mut ranking = get_popular(&kv, host);
+    let is_ranked = ranking.iter().any(|p| p.id == id);
+    if !is_ranked
+        && ranking.len() >= config::POPULAR_SIZE
+        && ranking.last().is_some_and(|p| p.views >= views)
+    {
+        return Ok(());
+    }
+
+    ranking.retain(|p| p.id != id);
+    ranking.push(PopularPaste {
+        id: id.to_string(),
+        filename: meta.filename.clone(),
+        views,
+        expires: expires_at(meta)
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs()),
+    });
+    ranking.sort_by_key(|p| std::cmp::Reverse(p.views));
+    ranking.truncate(config::POPULAR_SIZE);
+    kv.build_insert()
+        .execute(&popular_key(host), serde_json::to_string(&ranking)?)?;
+    Ok(())
+}
+
+/// Get the surrogate key of a paste's cached content and derived entries, ie `paste_{id}`
+#[inline(always)]
+fn surrogate_key(host: &str, id: &str) -> String {
+    tenant_key("paste", host, id)
+}
+
+/// Get a url without its trailing slashes, or `None` if it has none, ie `/p/{id}/` to `/p/{id}`
+#[inline(always)]
+fn without_trailing_slash(host: &str, url: &fastly::http::Url) -> Option<String> {
+    let path = url.path().strip_suffix('/')?.trim_end_matches('/');
+    let query = url.query().map(|q| format!("?{q}")).unwrap_or_default();
+    Some(format!("https://{host}{path}{query}"))
+}
+
+/// Get the filename of a paste path, the last segment after the id ignoring trailing slashes,
+/// ie `name.txt` from `/p/{id}/name.txt/`
+#[inline(always)]
+fn paste_filename(path: &str) -> Option<&str> {
+    path.split('/').filter(|v| !v.is_empty()).skip(2).last()
+}
+
+/// Check if an operator has blocked a paste
+#[inline(always)]
+fn is_blocked(kv: &KVStore, host: &str, id: &str) -> bool {
+    kv.lookup(&tenant_key("blocked", host, id)).is_ok()
+}
+
+/// Handle a request to get a paste
+#[inline(always)]
+fn handle_get(req: Request, nonce: &str) -> Result<Response, Error> {
+    let url = req.get_url();
+    let host = url.host_str().unwrap_or_default().to_string();
+    let theme = get_theme(&req);
+    let mut segments = url.path_segments().into_iter().flatten();
+    match segments.next() {
+        // Usage page
+        Some("") => {
+            let language = negotiate_language(req.get_header_str(header::ACCEPT_LANGUAGE));
+
+            // For all other clients other than curl, wrap with html (ie, browsers)
+            if let Some(agent) = req.get_header_str("user-agent") {
+                if !(agent.starts_with("curl") || agent.starts_with("Wget")) {
+                    let usage = get_usage(&host, true, language)?;
+                    let html = format!(
+                        include_str!("templates/index.html"),
+                        host = host,
+                        theme = theme,
+                        body = htmlescape::encode_minimal(&String::from_utf8_lossy(
+                            &usage.into_bytes()
+                        )),
+                        nonce = nonce
+                    );
+
+                    return Ok(Response::new()
+                        .with_body_text_html(&html)
+                        .with_header(header::VARY, "Accept-Language, Cookie"));
+                }
+            }
+
+            let mut usage = get_usage(&host, false, language)?;
+            // pipes and files keep the plain usage page
+            if has_query_flag(&req, "color") {
+                usage = colorize_usage(&usage, &host);
+            }
+            Ok(Response::new()
+                .with_body_text_plain(&usage)
+                .with_header(header::VARY, "Accept-Language"))
+        },
+
+        // Privacy policy page
+        Some("privacy") => {
+            const PRIVACY: &str = include_str!("static/privacy.txt");
+
+            // For all other clients other than curl, wrap with html (ie, browsers)
+            if let Some(agent) = req.get_header_str("user-agent") {
+                if !agent.starts_with("curl") {
+                    let html = format!(
+                        include_str!("templates/privacy.html"),
+                        host = host,
+                        theme = theme,
+                        body = PRIVACY
+                    );
+                    return Ok(Response::new().with_body_text_html(&html));
+                }
+            }
+
+            Ok(Response::new().with_body_text_plain(PRIVACY))
+        },
+
+        // Robots
+        Some("robots.txt") => {
+            const ROBOTS: &str = include_str!("static/robots.txt");
+            Ok(Response::new().with_body_text_plain(ROBOTS))
+        },
+
+        // Favicons
+        Some("favicon.ico") => {
+            const FAVICON: &[u8] = include_bytes!("static/icons8-paste-special.png");
+            Ok(with_static_headers(
+                Response::from_body(FAVICON).with_content_type(mime::IMAGE_PNG),
+            ))
+        },
+        Some("favicon.svg") => {
+            const FAVICON: &str = include_str!("static/favicon.svg");
+            Ok(with_static_headers(
+                Response::from_body(FAVICON).with_content_type(mime::IMAGE_SVG),
+            ))
+        },
+        Some("apple-touch-icon.png") => {
+            const ICON: &[u8] = include_bytes!("static/apple-touch-icon.png");
+            Ok(with_static_headers(
+                Response::from_body(ICON).with_content_type(mime::IMAGE_PNG),
+            ))
+        },
+
+        // Most viewed listed pastes
+        Some("popular") => {
+            let kv = open_kv(config::KV_STORE)?;
+            // blocked and deleted pastes stay ranked, but are never listed
+            let pastes: Vec<_> = get_popular(&kv, &host)
+                .into_iter()
+                .filter(|p| get_paste_meta(&host, &p.id).is_ok())
+                .collect();
+            let json = serde_json::to_string_pretty(&json!({ "pastes": pastes }))?;
+            Ok(Response::from_body(json)
+                .with_content_type(mime::APPLICATION_JSON)
+                .with_header(header::CACHE_CONTROL, "public, max-age=60"))
+        },
+
+        // JSON information page
+        Some("json") => {
+            let kv = open_kv(config::KV_STORE)?;
+            let cnt = get_upload_count(&kv, &host);
+            let stored = get_stored_bytes(&kv);
+            let features = [
+                ("markdown", true),
+                ("json", true),
+                ("diff", true),
+                ("ansi", true),
+                ("signed", true),
+                ("encrypted", true),
+                ("gzip", true),
+                ("delete", true),
+                ("report", true),
+                ("strip_exif", config::STRIP_EXIF),
+                ("sanitize_svg", config::SANITIZE_SVG),
+                ("strip_bom", config::STRIP_BOM),
+                ("compress_text", config::COMPRESS_TEXT),
+                ("tombstones", config::TOMBSTONES),
+                ("encrypt_at_rest", config::ENCRYPT_AT_REST),
+            ];
+            let json = serde_json::to_string_pretty(&json!({
+                "uploads": cnt,
+                "uploads_by_mime": get_mime_counts(&kv, &host),
+                "uploads_by_day": get_daily_counts(&kv, &host),
+                "uploads_by_size": get_size_counts(&kv, &host),
+                "version": env!("CARGO_PKG_VERSION"),
+                "service_version": std::env::var("FASTLY_SERVICE_VERSION").ok(),
+                "build_date": compile_time::date_str!(),
+                "id_size": id_size(),
+                "min_size": config::MIN_CONTENT_SIZE,
+                "max_size": config::MAX_CONTENT_SIZE,
+                "max_size_human": humanize_bytes_binary!(config::MAX_CONTENT_SIZE).to_string(),
+                "kv_ttl": format_duration(config::KV_TTL).to_string(),
+                "cache_ttl": format_duration(config::CACHE_TTL).to_string(),
+                "max_total_bytes": config::MAX_TOTAL_BYTES,
+                "storage_used": stored,
+                "storage_used_human": humanize_bytes_binary!(stored).to_string(),
+                "features": features
+                    .iter()
+                    .filter(|(_, enabled)| *enabled)
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>(),
+            }))?;
+            // Limits only change with a deploy, the upload count is allowed to be stale
+            Ok(Response::from_body(json)
+                .with_content_type(mime::APPLICATION_JSON)
+                .with_header(header::CACHE_CONTROL, "public, max-age=60"))
+        },
+
+        // Diff between two pastes
+        Some("diff") => {
+            let (Some(a), Some(b)) = (segments.next(), segments.next()) else {
+                return Ok(
+                    Response::from_status(404).with_body_text_plain("expected two paste ids")
+                );
+            };
+            let page = Page {
+                host: &host,
+                title: Some(&format!("{a} .. {b}")),
+                theme,
+                toc: false,
+                math: false,
+                nonce,
+                brotli: false,
+            };
+            get_diff(&host, a, b, is_browser(&req).then_some(&page))
+        },
+
+        // Download several pastes as one zip archive
+        Some("zip") => {
+            let ids: Vec<String> = req
+                .get_query_parameter("ids")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(str::to_string)
+                .collect();
+            if ids.is_empty() {
+                return Ok(Response::from_status(400).with_body_text_plain("expected ?ids=a,b,c"));
+            }
+            if ids.len() > config::MAX_ZIP_IDS {
+                return Ok(Response::from_status(400).with_body_text_plain(&format!(
+                    "at most {} ids can be zipped",
+                    config::MAX_ZIP_IDS
+                )));
+            }
+            get_zip(&host, &ids)
+        },
+
+        // Paste download
+        Some("p") => {
+            let Some(id) = segments.next().filter(|v| !v.is_empty()) else {
+                return Ok(Response::from_status(404).with_body_text_plain("expected paste id"));
+            };
+
+            // Send browsers to the url without trailing slashes, so relative links and shared
+            // urls stay clean
+            let location = without_trailing_slash(&host, url);
+            if let Some(location) = location.filter(|_| is_browser(&req)) {
+                return Ok(Response::from_status(301).with_header(header::LOCATION, location));
+            }
+            let is_json = has_query_flag(&req, "json");
+            // downloads are always the raw content
+            let is_download = has_query_flag(&req, "dl");
+            let disposition = if is_download { "attachment" } else { "inline" };
+            let is_raw = is_download || has_query_flag(&req, "raw");
+            // Filename in the url, markdown files are rendered in browsers by default
+            let last = paste_filename(url.path());
+            let is_markdown_file = last.is_some_and(|f| {
+                let f = f.to_ascii_lowercase();
+                f.ends_with(".md") || f.ends_with(".markdown")
+            });
+            // .txt urls are always plain text, ie to view markdown or html source
+            let is_plain = has_query_flag(&req, "txt")
+                || last.is_some_and(|f| f.to_ascii_lowercase().ends_with(".txt"));
+            // stored html is only rendered when asked for, since it runs on this origin
+            let is_render = has_query_flag(&req, "render");
+            let is_markdown = !is_json
+                && !is_raw
+                && !is_plain
+                && (has_query_flag(&req, "md") || is_markdown_file && is_browser(&req));
+            // ranges are always over the decoded content
+            let is_range = req.contains_header(header::RANGE);
+            let accept_encoding = req.get_header_str(header::ACCEPT_ENCODING);
+            let accept_gzip = !is_json && !is_range && accepts_encoding(accept_encoding, "gzip");
+            // terminals can ask for long lines to be hard wrapped
+            let cols = req
+                .get_query_parameter("cols")
+                .and_then(|c| c.parse::<usize>().ok())
+                .filter(|c| *c > 0 && !is_json && !is_markdown);
+
+            // Resized previews of image pastes, ie for social cards. Only a query flag, since any
+            // name in the path can be a filename
+            if has_query_flag(&req, "thumb") {
+                let width = req
+                    .get_query_parameter("w")
+                    .and_then(|w| w.parse().ok())
+                    .unwrap_or(config::THUMBNAIL_WIDTH)
+                    .clamp(16, config::MAX_THUMBNAIL_WIDTH);
+                return get_thumbnail(&req, &host, id, width);
+            }
+
+            // Only the content hash, ie to verify a paste out of band. Only a query flag, since
+            // any name in the path can be a filename
+            if has_query_flag(&req, "hash") {
+                return Ok(get_hash(&req, &host, id));
+            }
+
+            // Prefer the filename in the url, then the one given on upload
+            let get_filename = |meta: &FileMetadata| {
+                last.map(str::to_string)
+                    .or_else(|| meta.filename.clone())
+                    .unwrap_or_else(|| "no bs pastebin".to_string())
+            };
+            let page = Page {
+                host: &host,
+                title: last,
+                theme,
+                toc: has_query_flag(&req, "toc"),
+                math: has_query_flag(&req, "math"),
+                nonce,
+                brotli: !is_range && accepts_encoding(accept_encoding, "br"),
+            };
+
+            // Respond with only the headers when probing a paste, redirects need the content
+            if req.get_method() == Method::HEAD && !is_markdown && !is_json && cols.is_none() {
+                let mut meta = match get_paste_meta(&host, id)
+                    .and_then(|meta| verify_signature(&req, id, &meta).map(|_| meta))
+                {
+                    Ok(v) => v,
+                    Err(PasteError::NotFound) => return Ok(miscased_redirect(&req, &host, id)),
+                    Err(e) => return Ok(paste_error_response(id, e)),
+                };
+                if !verify_integrity(&req, &meta) {
+                    return Ok(integrity_error_response(&meta));
+                }
+                if meta.kind.as_deref() != Some("redirect") {
+                    let is_plain = is_served_plain(&meta.mime, is_plain, is_render);
+                    if is_plain && meta.encrypted.is_none() {
+                        meta.mime = Cow::from(mime::TEXT_PLAIN_UTF_8.as_ref());
+                    }
+                    let mut res = Response::new();
+                    if meta.encoding.is_some() && !accept_gzip {
+                        meta.encoding = None;
+                    }
+                    // the encoded length when served encoded, otherwise the decoded length
+                    if let Some(size) = paste_length(&meta) {
+                        res.set_header(header::CONTENT_LENGTH, size.to_string());
+                    }
+                    if meta.encoding.is_none() {
+                        res.set_header(header::ACCEPT_RANGES, "bytes");
+                    }
+                    if let Some(views) = get_views(&host, id, &meta) {
+                        res.set_header("x-views", views.to_string());
+                    }
+                    let filename = get_filename(&meta);
+                    let canonical = canonical_url(&host, id, last, &meta);
+                    return Ok(with_canonical(
+                        with_paste_headers(res, id, &meta, &filename, disposition),
+                        canonical.as_deref(),
+                    ));
+                }
+            }
+
+            let (content, mut meta) = match get_paste(
+                &host,
+                id,
+                accept_gzip,
+                is_markdown.then_some(&page),
+                cols,
+                is_plain,
+                is_render,
+            )
+            .and_then(|(content, meta)| verify_signature(&req, id, &meta).map(|_| (content, meta)))
+            {
+                Ok(v) => v,
+                Err(PasteError::NotFound) => return Ok(miscased_redirect(&req, &host, id)),
+                Err(e) => return Ok(paste_error_response(id, e)),
+            };
+            if !verify_integrity(&req, &meta) {
+                return Ok(integrity_error_response(&meta));
+            }
+
+            let accessed = touch_paste(&host, id);
+            // probes never count as a view
+            if req.get_method() != Method::HEAD {
+                if let Err(e) = record_view(&host, id, &meta) {
+                    return Ok(paste_error_response(id, e));
+                }
+            }
+            if is_json {
+                return get_paste_json(id, Body::from(content).into_bytes(), &meta, accessed);
+            }
+
+            // Shortened links redirect to their target, unless the raw link is requested
+            if meta.kind.as_deref() == Some("redirect") && !is_raw && !is_markdown {
+                let target = decode_content(content.into(), meta.encoding.as_deref())?;
+                let target = String::from_utf8_lossy(&target);
+                let target = target.trim();
+
+                // Show where the link goes instead of following it
+                if has_query_flag(&req, "preview") {
+                    if !is_browser(&req) {
+                        return Ok(Response::new().with_body_text_plain(&format!("{target}\n")));
+                    }
+                    let html = format!(
+                        include_str!("templates/preview.html"),
+                        head = page_head(),
+                        host = host,
+                        theme = theme,
+                        href = htmlescape::encode_attribute(target),
+                        target = htmlescape::encode_minimal(target),
+                    );
+                    return Ok(Response::new().with_body_text_html(&html));
+                }
+
+                return Ok(Response::from_status(302).with_header(header::LOCATION, target));
+            }
+            let filename = &get_filename(&meta);
+            let canonical = canonical_url(&host, id, last, &meta);
+
+            // Show browsers how to decrypt the paste locally, instead of downloading it
+            if let Some(scheme) = &meta.encrypted {
+                if is_browser(&req) && !is_raw {
+                    let query = req
+                        .get_query_str()
+                        .map(|q| q.to_string() + "&")
+                        .unwrap_or_default();
+                    let url = req.get_url_str();
+                    let html = format!(
+                        include_str!("templates/encrypted.html"),
+                        head = page_head(),
+                        title =
+                            htmlescape::encode_minimal(meta.title.as_deref().unwrap_or(filename)),
+                        canonical = canonical_tag(canonical.as_deref()),
+                        host = host,
+                        theme = theme,
+                        scheme = scheme,
+                        url = htmlescape::encode_minimal(url),
+                        raw_url = htmlescape::encode_minimal(&format!(
+                            "{}?{query}raw",
+                            url.split('?').next().unwrap_or_default()
+                        )),
+                    );
+                    return Ok(with_canonical(
+                        Response::new()
+                            .with_body_text_html(&html)
+                            .with_header("x-encrypted", scheme),
+                        canonical.as_deref(),
+                    ));
+                }
+            }
+
+            // Render colored terminal output for browsers, or text with a wrap override
+            let mut content = Body::from(content);
+            if is_browser(&req)
+                && !is_raw
+                && !is_markdown
+                && !is_plain
+                && meta.mime().starts_with("text/plain")
+            {
+                let bytes = decode_content(content, meta.encoding.take().as_deref())?;
+                let text = String::from_utf8_lossy(&bytes);
+                let wrap = if has_query_flag(&req, "nowrap") {
+                    Some(false)
+                } else if has_query_flag(&req, "wrap") {
+                    Some(true)
+                } else {
+                    None
+                };
+                let is_ansi = has_query_flag(&req, "ansi") || text.contains("\x1b[");
+
+                // Highlight code with an explicit, extension, or detected language
+                let lang = (!is_ansi)
+                    .then(|| {
+                        req.get_query_parameter("lang")
+                            .filter(|l| {
+                                !l.is_empty() && l.bytes().all(|b| b.is_ascii_alphanumeric())
+                            })
+                            .map(str::to_ascii_lowercase)
+                            .or_else(|| language_from_filename(filename).map(str::to_string))
+                            .or_else(|| detect_language(&text).map(str::to_string))
+                    })
+                    .flatten();
+                if let Some(lang) = lang {
+                    let html = format!(
+                        include_str!("templates/text.html"),
+                        head = page_head(),
+                        title =
+                            htmlescape::encode_minimal(meta.title.as_deref().unwrap_or(filename)),
+                        canonical = canonical_tag(canonical.as_deref()),
+                        host = host,
+                        theme = theme,
+                        white_space = if wrap.unwrap_or(false) {
+                            "pre-wrap"
+                        } else {
+                            "pre"
+                        },
+                        content = format!(
+                            r#"<code class="language-{lang}">{}</code>"#,
+                            htmlescape::encode_minimal(&text)
+                        ),
+                        scripts =
+                            highlight_assets(nonce, theme, req.get_query_parameter("hltheme")),
+                    );
+                    meta.mime = Cow::from("text/html");
+                    return Ok(with_canonical(
+                        with_browser_page(with_weak_etag(
+                            with_paste_headers(
+                                Response::from_body(html),
+                                id,
+                                &meta,
+                                filename,
+                                disposition,
+                            ),
+                            &meta,
+                        )),
+                        canonical.as_deref(),
+                    ));
+                }
 
-            // For all other clients other than curl, wrap with html (ie, browsers)
-            if let Some(agent) = req.get_header_str("user-agent") {
-                if !agent.starts_with("curl") {
+                if wrap.is_some() || is_ansi {
+                    // non-ansi text is escaped by the converter, and malformed escape sequences
+                    // fall back to the escaped text
+                    let content = ansi_to_html::convert(&text)
+                        .unwrap_or_else(|_| htmlescape::encode_minimal(&text));
                     let html = format!(
-                        include_str!("templates/privacy.html"),
+                        include_str!("templates/text.html"),
+                        head = page_head(),
+                        title =
+                            htmlescape::encode_minimal(meta.title.as_deref().unwrap_or(filename)),
+                        canonical = canonical_tag(canonical.as_deref()),
                         host = host,
-                        body = PRIVACY
+                        theme = theme,
+                        white_space = if wrap.unwrap_or_else(|| is_prose(&text)) {
+                            "pre-wrap"
+                        } else {
+                            "pre"
+                        },
+                        content = content,
+                        scripts = "",
                     );
-                    return Ok(Response::new().with_body_text_html(&html));
+                    meta.mime = Cow::from("text/html");
+                    return Ok(with_canonical(
+                        with_browser_page(with_weak_etag(
+                            with_paste_headers(
+                                Response::from_body(html),
+                                id,
+                                &meta,
+                                filename,
+                                disposition,
+                            ),
+                            &meta,
+                        )),
+                        canonical.as_deref(),
+                    ));
+                }
+                content = bytes.into();
+            }
+
+            let mut res = with_canonical(
+                with_paste_headers(
+                    Response::from_body(content),
+                    id,
+                    &meta,
+                    filename,
+                    disposition,
+                ),
+                canonical.as_deref(),
+            );
+            // Rendered and wrapped bodies are another representation of the paste
+            if is_markdown || cols.is_some() {
+                res = with_weak_etag(res, &meta);
+            }
+            // Markdown files are only rendered for browsers, with their theme
+            if is_markdown {
+                res = with_browser_page(res);
+            }
+            // Rendered html can't run scripts, submit forms, or reach the origin's storage
+            if is_render && !is_markdown && is_html(&meta.mime) {
+                res.set_header(
+                    header::CONTENT_SECURITY_POLICY,
+                    "sandbox; default-src * data:; style-src * 'unsafe-inline'; script-src 'none'",
+                );
+            }
+            // Content served as stored has a known length, instead of a chunked transfer
+            let length = (!is_markdown && cols.is_none())
+                .then(|| paste_length(&meta))
+                .flatten();
+            if meta.encoding.is_some() {
+                if let Some(length) = length {
+                    res.set_header(header::CONTENT_LENGTH, length.to_string());
                 }
+                return Ok(res);
+            }
+            let mut res = res.with_header(header::ACCEPT_RANGES, "bytes");
+            let if_range = req.get_header_str(header::IF_RANGE);
+            let etag = res.get_header_str(header::ETAG);
+            let last_modified = res.get_header_str(header::LAST_MODIFIED);
+            if is_range && if_range_matches(if_range, etag, last_modified) {
+                return Ok(with_range(&req, res));
+            }
+            if let Some(length) = length {
+                res.set_header(header::CONTENT_LENGTH, length.to_string());
+            }
+            Ok(res)
+        },
+
+        // Unknown path
+        Some(p) => {
+            let mut msg = format!("{p} not found\n");
+            if let Some(url) = suggest_paste_url(&host, p, url.path()) {
+                msg += &format!("did you mean {url} ?\n");
+            }
+            msg += &format!("see https://{host}/ for usage\n");
+            Ok(Response::from_status(404).with_body_text_plain(&msg))
+        },
+        None => unreachable!(),
+    }
+}
+
+/// Get the canonical url of a paste requested without a filename, when one was given on upload
+#[inline(always)]
+fn canonical_url(host: &str, id: &str, last: Option<&str>, meta: &FileMetadata) -> Option<String> {
+    match (last, &meta.filename) {
+        (None, Some(filename)) => Some(format!("https://{host}/p/{id}/{filename}")),
+        _ => None,
+    }
+}
+
+/// Link to the canonical url of a paste in the response headers
+#[inline(always)]
+fn with_canonical(res: Response, canonical: Option<&str>) -> Response {
+    match canonical {
+        Some(url) => res.with_header(header::LINK, format!(r#"<{url}>; rel="canonical""#)),
+        None => res,
+    }
+}
+
+/// Get the canonical link tag for html views, or nothing
+#[inline(always)]
+fn canonical_tag(canonical: Option<&str>) -> String {
+    canonical
+        .map(|url| {
+            format!(
+                r#"<link rel="canonical" href="{}">"#,
+                htmlescape::encode_attribute(url)
+            )
+        })
+        .unwrap_or_default()
+}
+
+/// Check if the `If-Range` validator matches the response's etag or last modified date, or is
+/// absent. Responses with a weak etag are transformed from the paste, so they never match, even
+/// by date.
+#[inline(always)]
+fn if_range_matches(
+    if_range: Option<&str>,
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+) -> bool {
+    let Some(validator) = if_range else {
+        return true;
+    };
+    // strong comparison
+    let Some(etag) = etag.filter(|etag| !etag.starts_with("W/")) else {
+        return false;
+    };
+    etag == validator || last_modified == Some(validator)
+}
+
+/// Parse a single byte range of a body from a range header. `None` if the range is invalid or
+/// multiple ranges are requested, and `Some(None)` if it can't be satisfied.
+#[inline(always)]
+fn byte_range(range: &str, len: usize) -> Option<Option<std::ops::Range<usize>>> {
+    let spec = range
+        .trim()
+        .strip_prefix("bytes=")
+        .filter(|v| !v.contains(','))?;
+    let (start, end) = spec.trim().split_once('-')?;
+    match (start.parse::<usize>(), end.parse::<usize>()) {
+        // suffix range, ie the last n bytes
+        (Err(_), Ok(n)) if start.is_empty() => {
+            Some((n > 0 && len > 0).then(|| len - n.min(len)..len))
+        },
+        (Ok(start), Err(_)) if end.is_empty() => Some((start < len).then_some(start..len)),
+        (Ok(start), Ok(end)) if start <= end => {
+            Some((start < len).then(|| start..end.saturating_add(1).min(len)))
+        },
+        _ => None,
+    }
+}
+
+/// Serve a single byte range of a response body. Invalid or multiple ranges are ignored and the
+/// full body is served, unsatisfiable ranges respond with 416.
+#[inline(always)]
+fn with_range(req: &Request, mut res: Response) -> Response {
+    let Some(value) = req.get_header_str(header::RANGE) else {
+        return res;
+    };
+    let body = res.take_body_bytes();
+    let len = body.len();
+    let Some(range) = byte_range(value, len) else {
+        return res.with_body(body);
+    };
+
+    match range {
+        Some(range) => {
+            res.set_status(206);
+            res.set_header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{len}", range.start, range.end - 1),
+            );
+            res.with_body(body[range].to_vec())
+        },
+        None => {
+            res.set_status(416);
+            res.set_header(header::CONTENT_RANGE, format!("bytes */{len}"));
+            res
+        },
+    }
+}
+
+/// Get the length of a paste as served, the encoded length if it's served encoded
+#[inline(always)]
+fn paste_length(meta: &FileMetadata) -> Option<usize> {
+    match meta.encoding {
+        Some(_) => meta.encoded_size,
+        None => meta.size,
+    }
+}
+
+/// Hard wrap lines longer than a number of columns, preserving existing newlines
+#[inline(always)]
+fn wrap_lines(text: &str, cols: usize) -> String {
+    let mut wrapped = String::with_capacity(text.len() + text.len() / cols);
+    for line in text.split_inclusive('\n') {
+        let mut width = 0;
+        for c in line.chars() {
+            if c != '\n' && width == cols {
+                wrapped.push('\n');
+                width = 0;
+            }
+            wrapped.push(c);
+            width += 1;
+        }
+    }
+    wrapped
+}
+
+/// Get the highlight.js language for a filename extension
+#[inline(always)]
+fn language_from_filename(filename: &str) -> Option<&'static str> {
+    let (_, ext) = filename.rsplit_once('.')?;
+    Some(match ext.to_ascii_lowercase().as_str() {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "ts" => "typescript",
+        "go" => "go",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "hpp" => "cpp",
+        "java" => "java",
+        "rb" => "ruby",
+        "sh" | "bash" | "zsh" => "bash",
+        "json" => "json",
+        "toml" => "ini",
+        "yml" | "yaml" => "yaml",
+        "html" | "xml" | "svg" => "xml",
+        "css" => "css",
+        "sql" => "sql",
+        "diff" | "patch" => "diff",
+        "nix" => "nix",
+        _ => return None,
+    })
+}
+
+/// Guess the language of filename-less code from a shebang or common keywords. Only returns a
+/// language when one clearly scores higher than the others.
+#[inline(always)]
+fn detect_language(text: &str) -> Option<&'static str> {
+    let first = text.lines().next().unwrap_or_default();
+    if let Some(shebang) = first.strip_prefix("#!") {
+        return [
+            ("python", "python"),
+            ("bash", "bash"),
+            ("sh", "bash"),
+            ("node", "javascript"),
+            ("ruby", "ruby"),
+            ("perl", "perl"),
+        ]
+        .into_iter()
+        .find(|(interpreter, _)| {
+            shebang
+                .split(['/', ' '])
+                .any(|w| w.starts_with(interpreter))
+        })
+        .map(|(_, lang)| lang);
+    }
+    if text.starts_with("diff --git") || text.starts_with("--- ") && text.contains("\n+++ ") {
+        return Some("diff");
+    }
+    if text.starts_with("<?xml") || text.starts_with("<!DOCTYPE") || text.starts_with("<html") {
+        return Some("xml");
+    }
+    if (text.starts_with('{') || text.starts_with('['))
+        && serde_json::from_str::<serde_json::Value>(text).is_ok()
+    {
+        return Some("json");
+    }
+
+    const KEYWORDS: &[(&str, &[&str])] = &[
+        (
+            "rust",
+            &[
+                "fn ",
+                "let mut ",
+                "impl ",
+                "pub fn ",
+                "use std::",
+                "::new(",
+                "-> ",
+            ],
+        ),
+        (
+            "python",
+            &["def ", "import ", "self.", "elif ", "print(", "__init__"],
+        ),
+        (
+            "javascript",
+            &[
+                "function ",
+                "const ",
+                "=> ",
+                "console.log",
+                "require(",
+                "let ",
+            ],
+        ),
+        ("go", &["package ", "func ", ":= ", "fmt.", "import ("]),
+        ("c", &["#include", "int main", "printf(", "malloc(", "->"]),
+    ];
+    let mut scores: Vec<_> = KEYWORDS
+        .iter()
+        .map(|(lang, words)| (words.iter().filter(|w| text.contains(*w)).count(), *lang))
+        .collect();
+    scores.sort_unstable_by(|a, b| b.cmp(a));
+    match scores[..] {
+        [(best, lang), (next, _), ..] if best >= 3 && best > next + 1 => Some(lang),
+        _ => None,
+    }
+}
+
+/// Get the highlight.js stylesheets and scripts for a color scheme, or a named highlight theme,
+/// carrying the csp nonce. Unknown theme names fall back to the color scheme.
+#[inline(always)]
+fn highlight_assets(nonce: &str, theme: &str, hltheme: Option<&str>) -> String {
+    const CDN: &str = "https://cdn.jsdelivr.net/gh/highlightjs/cdn-release@11.9.0/build";
+    const THEMES: &[&str] = &[
+        "atom-one-dark",
+        "atom-one-light",
+        "github",
+        "github-dark",
+        "github-dark-dimmed",
+        "monokai",
+        "nord",
+        "stackoverflow-dark",
+        "stackoverflow-light",
+        "tokyo-night-dark",
+        "tokyo-night-light",
+        "vs",
+        "vs2015",
+    ];
+    let hltheme = hltheme.and_then(|name| THEMES.iter().find(|t| name.eq_ignore_ascii_case(t)));
+    let styles = if let Some(name) = hltheme {
+        format!(r#"<link rel="stylesheet" href="{CDN}/styles/{name}.min.css">"#)
+    } else {
+        match theme {
+            "dark" => format!(r#"<link rel="stylesheet" href="{CDN}/styles/github-dark.min.css">"#),
+            "light" => format!(r#"<link rel="stylesheet" href="{CDN}/styles/github.min.css">"#),
+            _ => format!(
+                r#"<link rel="stylesheet" href="{CDN}/styles/github-dark.min.css" media="(prefers-color-scheme: dark)">
+    <link rel="stylesheet" href="{CDN}/styles/github.min.css" media="(prefers-color-scheme: light)">"#
+            ),
+        }
+    };
+    format!(
+        r#"{styles}
+    <script nonce="{nonce}" src="{CDN}/highlight.min.js"></script>
+    <script nonce="{nonce}">hljs.highlightAll();</script>"#
+    )
+}
+
+/// Get the katex stylesheet and scripts rendering math elements, carrying the csp nonce
+#[inline(always)]
+fn katex_assets(nonce: &str) -> String {
+    const CDN: &str = "https://cdn.jsdelivr.net/npm/katex@0.16.11/dist";
+    format!(
+        r#"<link rel="stylesheet" href="{CDN}/katex.min.css">
+    <script nonce="{nonce}" src="{CDN}/katex.min.js"></script>
+    <script nonce="{nonce}">
+        addEventListener("DOMContentLoaded", () => document.querySelectorAll("code.language-math").forEach(el => {{
+            const display = el.classList.contains("math-display");
+            const out = document.createElement(display ? "div" : "span");
+            katex.render(el.textContent, out, {{ displayMode: display, throwOnError: false }});
+            (display ? el.parentElement : el).replaceWith(out);
+        }}));
+    </script>"#
+    )
+}
+
+/// Get the mermaid script rendering ```mermaid code blocks as diagrams, carrying the csp nonce
+#[inline(always)]
+fn mermaid_assets(nonce: &str, theme: &str) -> String {
+    const CDN: &str = "https://cdn.jsdelivr.net/npm/mermaid@11.4.1/dist";
+    format!(
+        r#"<script nonce="{nonce}" src="{CDN}/mermaid.min.js"></script>
+    <script nonce="{nonce}">
+        addEventListener("DOMContentLoaded", () => {{
+            const dark = "{theme}" === "dark" || ("{theme}" !== "light" && matchMedia("(prefers-color-scheme: dark)").matches);
+            document.querySelectorAll("code.language-mermaid").forEach(el => {{
+                const diagram = document.createElement("pre");
+                diagram.className = "mermaid";
+                diagram.textContent = el.textContent;
+                el.parentElement.replaceWith(diagram);
+            }});
+            mermaid.initialize({{ startOnLoad: false, theme: dark ? "dark" : "default" }});
+            mermaid.run();
+        }});
+    </script>"#
+    )
+}
+
+/// Guess the charset of text content, or `None` if it's neither utf-8 or marked as utf-16
+#[inline(always)]
+fn detect_charset(content: &[u8]) -> Option<&'static str> {
+    match content {
+        [0xFF, 0xFE, ..] => Some("utf-16le"),
+        [0xFE, 0xFF, ..] => Some("utf-16be"),
+        _ => std::str::from_utf8(content).is_ok().then_some("utf-8"),
+    }
+}
+
+/// Check if a mime type is rendered as a html document by browsers
+#[inline(always)]
+fn is_html(mime: &str) -> bool {
+    mime.starts_with("text/html") || mime.starts_with("application/xhtml+xml")
+}
+
+/// Check if a paste is served as plain text, when asked for or for stored html, which would run
+/// on this origin unless it's explicitly rendered in a sandbox
+#[inline(always)]
+fn is_served_plain(mime: &str, plain: bool, render: bool) -> bool {
+    plain || !render && is_html(mime)
+}
+
+/// Guess if text is prose rather than code or logs, ie few lines are indented
+#[inline(always)]
+fn is_prose(text: &str) -> bool {
+    let (lines, indented) = text
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .fold((0, 0), |(lines, indented), l| {
+            (lines + 1, indented + l.starts_with([' ', '\t']) as usize)
+        });
+    indented * 10 < lines
+}
+
+/// Redirect to the stored id of a paste requested with the wrong case, or respond with not
+/// found when there isn't exactly one stored id matching.
+#[inline(always)]
+fn miscased_redirect(req: &Request, host: &str, id: &str) -> Response {
+    let lower = id.to_lowercase();
+    let candidates = config::CASE_INSENSITIVE_IDS
+        .then(|| {
+            kv_for(&lower)
+                .ok()?
+                .lookup(&tenant_key("lower", host, &lower))
+                .ok()
+        })
+        .flatten()
+        .map(|mut v| v.take_body().into_string())
+        .unwrap_or_default();
+    let mut candidates: Vec<_> = candidates.lines().filter(|c| *c != id).collect();
+    candidates.sort_unstable();
+    candidates.dedup();
+
+    match candidates[..] {
+        [canonical]
+            if kv_for(canonical)
+                .is_ok_and(|kv| kv.lookup(&tenant_key("file", host, canonical)).is_ok()) =>
+        {
+            let path = req
+                .get_path()
+                .replacen(&format!("/p/{id}"), &format!("/p/{canonical}"), 1);
+            let query = req
+                .get_query_str()
+                .map(|q| format!("?{q}"))
+                .unwrap_or_default();
+            Response::from_status(301)
+                .with_header(header::LOCATION, format!("https://{host}{path}{query}"))
+        },
+        _ => paste_error_response(id, PasteError::NotFound),
+    }
+}
+
+/// Build the response for a paste that can't be served
+#[inline(always)]
+fn paste_error_response(id: &str, err: PasteError) -> Response {
+    match err {
+        PasteError::Blocked => Response::from_status(451)
+            .with_body_text_plain(&format!("{id} is unavailable for legal reasons")),
+        PasteError::NotFound => {
+            Response::from_status(404).with_body_text_plain(&format!("{id} not found"))
+        },
+        PasteError::InvalidSignature => {
+            Response::from_status(403).with_body_text_plain("invalid or expired signature")
+        },
+        PasteError::Deleted => {
+            Response::from_status(410).with_body_text_plain(&format!("{id} was deleted"))
+        },
+        PasteError::NotText => {
+            Response::from_status(415).with_body_text_plain(&format!("{id} is not a text paste"))
+        },
+        PasteError::Unavailable => {
+            Response::from_status(503).with_body_text_plain("paste storage is not configured")
+        },
+        PasteError::Failed(e) => {
+            log!("failed to load {id}: {e}");
+            Response::from_status(500).with_body_text_plain(&format!("failed to load {id}"))
+        },
+    }
+}
+
+/// Check the paste hash against a client's `?integrity=blake3-<base64>`, if given. Both base64
+/// alphabets are accepted, since `+` is often decoded from queries as a space.
+#[inline(always)]
+fn verify_integrity(req: &Request, meta: &FileMetadata) -> bool {
+    use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD};
+
+    let Some(integrity) = req.get_query_parameter("integrity") else {
+        return true;
+    };
+    let Some(encoded) = integrity.trim().strip_prefix("blake3-") else {
+        return false;
+    };
+    let encoded = encoded.replace(' ', "+");
+    [STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD]
+        .iter()
+        .find_map(|engine| engine.decode(&encoded).ok())
+        .is_some_and(|hash| hash == meta.hash)
+}
+
+/// Respond to a mismatched integrity query with the stored hash
+#[inline(always)]
+fn integrity_error_response(meta: &FileMetadata) -> Response {
+    let hash = base64::engine::general_purpose::STANDARD.encode(meta.hash);
+    Response::from_status(422)
+        .with_header("x-existing-hash", format!("blake3-{hash}"))
+        .with_body_text_plain(&format!("integrity mismatch\nstored: blake3-{hash}\n"))
+}
+
+/// Get when a paste expires from storage, from the upload's expiry or its creation time
+#[inline(always)]
+fn expires_at(meta: &FileMetadata) -> Option<SystemTime> {
+    if let Some(expires) = meta.expires {
+        return Some(SystemTime::UNIX_EPOCH + Duration::from_secs(expires));
+    }
+    let created = SystemTime::UNIX_EPOCH + Duration::from_secs(meta.created?);
+    Some(created + config::KV_TTL)
+}
+
+/// Check if a paste has outlived its expiry, but is still in the cache or not yet evicted
+#[inline(always)]
+fn is_expired(meta: &FileMetadata) -> bool {
+    expires_at(meta).is_some_and(|t| t <= SystemTime::now())
+}
+
+/// Get how long to cache a paste and entries derived from it, never past its expiry
+#[inline(always)]
+fn cache_ttl(meta: &FileMetadata) -> Duration {
+    expires_at(meta)
+        .and_then(|t| t.duration_since(SystemTime::now()).ok())
+        .map_or(config::CACHE_TTL, |ttl| ttl.min(config::CACHE_TTL))
+        .max(Duration::from_secs(1))
+}
+
+/// Longest time shared caches keep immutable responses, in seconds
+const SHARED_MAX_AGE: u64 = 31536000;
+
+/// Get how long shared caches may keep a paste response in seconds, or `None` if they never may.
+/// Content never changes, but signed pastes would be served past the url's expiry, pastes
+/// limited to a number of views past their last view, and pastes with an expiry time are only
+/// cached until then.
+#[inline(always)]
+fn shared_max_age(meta: &FileMetadata) -> Option<u64> {
+    if meta.signed || meta.max_views.is_some() {
+        return None;
+    }
+    Some(match meta.expires {
+        Some(_) => cache_ttl(meta).as_secs().min(SHARED_MAX_AGE),
+        None => SHARED_MAX_AGE,
+    })
+}
+
+/// Get the cache control for a shared cache lifetime, never storing responses without one
+#[inline(always)]
+fn cache_control(max_age: Option<u64>) -> String {
+    match max_age {
+        Some(max_age) => format!("public, s-maxage={max_age}, immutable"),
+        None => "private, no-store".to_string(),
+    }
+}
+
+/// Get the cache control of a paste response
+#[inline(always)]
+fn paste_cache_control(meta: &FileMetadata) -> String {
+    cache_control(shared_max_age(meta))
+}
+
+/// Set the caching, content, and metadata headers for a paste response
+#[inline(always)]
+fn with_paste_headers(
+    mut res: Response,
+    id: &str,
+    meta: &FileMetadata,
+    filename: &str,
+    disposition: &str,
+) -> Response {
+    // ids and full hashes, to correlate responses without the url
+    res.set_header("x-paste-id", id);
+    res.set_header("x-paste-hash", encode_id(&meta.hash));
+    if let Some((width, height)) = meta.dims {
+        res.set_header("x-image-width", width.to_string());
+        res.set_header("x-image-height", height.to_string());
+    }
+    if let Some(scheme) = &meta.encrypted {
+        res.set_header("x-encrypted", scheme);
+    }
+    // replaced with the frame policy of the response in main
+    if let Some(origins) = &meta.embed {
+        res.set_header("x-frame-ancestors", origins);
+    }
+    if let Some(encoding) = &meta.encoding {
+        res.set_header(header::CONTENT_ENCODING, encoding);
+    }
+    // Svgs can never run scripts or load external resources, even when opened directly
+    if meta.mime.starts_with("image/svg") {
+        res.set_header(
+            header::CONTENT_SECURITY_POLICY,
+            "default-src 'none'; style-src 'unsafe-inline'; img-src data:; sandbox",
+        );
+    }
+    if let Some(created) = meta.created {
+        res.set_header(
+            header::LAST_MODIFIED,
+            httpdate::fmt_http_date(SystemTime::UNIX_EPOCH + Duration::from_secs(created)),
+        );
+    }
+    if let Some(expires) = expires_at(meta) {
+        res.set_header("x-expires", httpdate::fmt_http_date(expires));
+        res.set_header(
+            "x-expires-at",
+            humantime::format_rfc3339_seconds(expires).to_string(),
+        );
+        // remaining lifetime in whole seconds, ie "13days 23h 59m 59s", only on responses that
+        // aren't stored by shared caches, since cached copies would keep counting from then
+        if shared_max_age(meta).is_none() {
+            let remaining = expires
+                .duration_since(SystemTime::now())
+                .map(|d| Duration::from_secs(d.as_secs()))
+                .unwrap_or_default();
+            res.set_header("x-expires-in", format_duration(remaining).to_string());
+        }
+    }
+
+    res
+        // Immutable client caching, unless only some clients may see the paste
+        .with_header(header::CACHE_CONTROL, paste_cache_control(meta))
+        .with_header(header::ETAG, paste_etag(meta))
+        // Stored content may be compressed, and browsers are served pages of some pastes
+        .with_header(header::VARY, "Accept-Encoding, Accept, User-Agent")
+        // Content type and disposition (for "filename" on certain browsers)
+        .with_header(header::CONTENT_TYPE, meta.mime())
+        // Some browsers will set the title to this header, attachments are always downloaded
+        .with_header(
+            header::CONTENT_DISPOSITION,
+            format!(
+                r#"{disposition}; filename="{filename}"; filename*=UTF-8''{}"#,
+                urlencoding::encode(filename)
+            ),
+        )
+}
+
+/// Get the strong etag of a paste as served. The content hash never changes for a paste, and
+/// encoded content is another representation.
+#[inline(always)]
+fn paste_etag(meta: &FileMetadata) -> String {
+    match &meta.encoding {
+        Some(encoding) => format!(r#""{}-{encoding}""#, bs58::encode(meta.hash).into_string()),
+        None => format!(r#""{}""#, bs58::encode(meta.hash).into_string()),
+    }
+}
+
+/// Replace the etag of a paste response transformed from the stored content, ie rendered markdown
+/// or wrapped text, with a weak etag of the content hash
+#[inline(always)]
+fn with_weak_etag(mut res: Response, meta: &FileMetadata) -> Response {
+    res.set_header(
+        header::ETAG,
+        format!(r#"W/"{}""#, bs58::encode(meta.hash).into_string()),
+    );
+    res
+}
+
+/// Keep a page rendered for browsers out of shared caches, since other clients are served the
+/// paste itself from the same url, and its theme follows the browser's cookie
+#[inline(always)]
+fn with_browser_page(mut res: Response) -> Response {
+    res.set_header(header::CACHE_CONTROL, cache_control(None));
+    res.append_header(header::VARY, "Cookie");
+    res
+}
+
+/// Get a paste's content and metadata as a json object. Text content is inlined, and binary
+/// content is encoded with base64.
+#[inline(always)]
+fn get_paste_json(
+    id: &str,
+    content: Vec<u8>,
+    meta: &FileMetadata,
+    accessed: Option<u64>,
+) -> Result<Response, Error> {
+    let size = content.len();
+    let (encoding, content) = match String::from_utf8(content) {
+        Ok(text) => ("utf-8", text),
+        Err(e) => (
+            "base64",
+            base64::engine::general_purpose::STANDARD.encode(e.into_bytes()),
+        ),
+    };
+
+    // Short escaped teaser for listings, binary content is only described
+    let preview = if encoding == "utf-8" {
+        let mut preview: String = content.chars().take(config::PREVIEW_SIZE).collect();
+        if preview.len() < content.len() {
+            preview.push('…');
+        }
+        htmlescape::encode_minimal(&preview)
+    } else {
+        format!("{} binary, {size} bytes", meta.mime())
+    };
+
+    // Text statistics, binary content only reports the byte size
+    let stats = (encoding == "utf-8").then(|| {
+        json!({
+            "lines": content.lines().count(),
+            "chars": content.chars().count(),
+            "charset": if content.is_ascii() { "us-ascii" } else { "utf-8" },
+        })
+    });
+
+    let json = serde_json::to_string_pretty(&json!({
+        "id": id,
+        "mime": meta.mime(),
+        "size": size,
+        "hash": format!("blake3-{}", base64::engine::general_purpose::STANDARD.encode(meta.hash)),
+        "created": meta.created.map(|secs| {
+            humantime::format_rfc3339_seconds(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                .to_string()
+        }),
+        "expires": expires_at(meta)
+            .map(|expires| humantime::format_rfc3339_seconds(expires).to_string()),
+        "accessed": accessed.map(|secs| {
+            humantime::format_rfc3339_seconds(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                .to_string()
+        }),
+        "dims": meta.dims.map(|(width, height)| json!({ "width": width, "height": height })),
+        "encrypted": meta.encrypted,
+        "title": meta.title,
+        "filename": meta.filename,
+        "unlisted": meta.unlisted,
+        "stats": stats,
+        "preview": preview,
+        "encoding": encoding,
+        "content": content,
+    }))?;
+
+    Ok(Response::from_body(json)
+        .with_content_type(mime::APPLICATION_JSON)
+        .with_header(header::CACHE_CONTROL, json_cache_control(meta)))
+}
+
+/// Get the cache control of a paste's json object. The last accessed time changes, everything
+/// else is cached like the paste itself.
+#[inline(always)]
+fn json_cache_control(meta: &FileMetadata) -> String {
+    match shared_max_age(meta) {
+        Some(max_age) => format!("public, max-age={}", max_age.min(60)),
+        None => cache_control(None),
+    }
+}
+
+/// Collect the depth and text of all headings in a markdown tree, in document order
+#[inline(always)]
+fn collect_headings(node: &markdown::mdast::Node, headings: &mut Vec<(u8, String)>) {
+    if let markdown::mdast::Node::Heading(heading) = node {
+        headings.push((heading.depth, node.to_string()));
+    }
+    for child in node.children().into_iter().flatten() {
+        collect_headings(child, headings);
+    }
+}
+
+/// Give each rendered heading a unique anchor id, and prepend a collapsible table of contents
+/// linking to them. Headings must be in the same order they were rendered.
+#[inline(always)]
+fn add_table_of_contents(html: &str, headings: &[(u8, String)]) -> String {
+    let mut ids = std::collections::HashSet::new();
+    let mut toc = String::from(r#"<details class="toc" open><summary>Contents</summary><ul>"#);
+    let mut out = String::with_capacity(html.len() + headings.len() * 64);
+    let mut rest = html;
+
+    for (depth, text) in headings {
+        let tag = format!("<h{depth}>");
+        let Some(pos) = rest.find(&tag) else {
+            continue;
+        };
+
+        // Sanitize the heading into a lowercase slug, deduplicated with a numeric suffix
+        let mut slug = String::new();
+        for c in text.trim().chars().flat_map(char::to_lowercase) {
+            if c.is_alphanumeric() {
+                slug.push(c);
+            } else if (c.is_whitespace() || c == '-' || c == '_') && !slug.ends_with('-') {
+                slug.push('-');
             }
+        }
+        let base = match slug.trim_matches('-') {
+            "" => "section".to_string(),
+            s => s.to_string(),
+        };
+        let mut id = base.clone();
+        let mut n = 1;
+        while !ids.insert(id.clone()) {
+            id = format!("{base}-{n}");
+            n += 1;
+        }
+
+        out.push_str(&rest[..pos]);
+        out.push_str(&format!(r#"<h{depth} id="{id}">"#));
+        rest = &rest[pos + tag.len()..];
+        toc.push_str(&format!(
+            r##"<li class="toc-h{depth}"><a href="#{id}">{}</a></li>"##,
+            htmlescape::encode_minimal(text)
+        ));
+    }
+    out.push_str(rest);
+    toc.push_str("</ul></details>\n");
+    toc.push_str(&out);
+    toc
+}
+
+/// Get a unified diff between two text pastes, wrapped with html for browsers.
+#[inline(always)]
+fn get_diff(host: &str, a: &str, b: &str, page: Option<&Page>) -> Result<Response, Error> {
+    let mut texts = Vec::with_capacity(2);
+    let mut max_age = Some(SHARED_MAX_AGE);
+    for id in [a, b] {
+        let content = match get_paste(host, id, false, None, None, false, false) {
+            // signed pastes are only served through their signed url
+            Ok((_, meta)) if meta.signed => {
+                return Ok(paste_error_response(id, PasteError::InvalidSignature));
+            },
+            Ok((content, meta)) => match record_view(host, id, &meta) {
+                Ok(_) => {
+                    max_age = max_age.min(shared_max_age(&meta));
+                    Body::from(content).into_bytes()
+                },
+                Err(e) => return Ok(paste_error_response(id, e)),
+            },
+            Err(e) => return Ok(paste_error_response(id, e)),
+        };
+        let Ok(text) = String::from_utf8(content) else {
+            return Ok(Response::from_status(415)
+                .with_body_text_plain(&format!("{id} is not a text paste")));
+        };
+        texts.push(text);
+    }
+
+    let diff = TextDiff::from_lines(&texts[0], &texts[1])
+        .unified_diff()
+        .header(a, b)
+        .to_string();
+
+    let res = if let Some(page) = page {
+        // Highlight each line based on the unified diff prefix
+        let body: String = diff
+            .lines()
+            .map(|line| {
+                let class = match line.as_bytes().first() {
+                    _ if line.starts_with("---") || line.starts_with("+++") => "file",
+                    Some(b'@') => "hunk",
+                    Some(b'+') => "add",
+                    Some(b'-') => "del",
+                    _ => "",
+                };
+                format!(
+                    r#"<span class="{class}">{}</span>"#,
+                    htmlescape::encode_minimal(line)
+                )
+            })
+            .collect();
+        let html = format!(
+            include_str!("templates/diff.html"),
+            head = page_head(),
+            title = htmlescape::encode_minimal(page.title.unwrap_or_default()),
+            host = page.host,
+            theme = page.theme,
+            body = body
+        );
+        Response::new().with_body_text_html(&html)
+    } else {
+        Response::new().with_body_text_plain(&diff)
+    };
+
+    // Both sides are immutable, so the diff is too, but only cached as long as both are
+    Ok(res.with_header(header::CACHE_CONTROL, cache_control(max_age)))
+}
+
+/// Get a webp thumbnail of an image paste, fit within a width, and cached under a key derived
+/// from the paste.
+#[inline(always)]
+fn get_thumbnail(req: &Request, host: &str, id: &str, width: u32) -> Result<Response, Error> {
+    let meta = match get_paste_meta(host, id)
+        .and_then(|meta| verify_signature(req, id, &meta).map(|_| meta))
+    {
+        Ok(meta) => meta,
+        Err(e) => return Ok(paste_error_response(id, e)),
+    };
+    if !meta.mime.starts_with("image/") || meta.encrypted.is_some() {
+        return Ok(
+            Response::from_status(415).with_body_text_plain(&format!("{id} is not an image paste"))
+        );
+    }
+    // cached thumbnails are a view of the paste too
+    if let Err(e) = record_view(host, id, &meta) {
+        return Ok(paste_error_response(id, e));
+    }
+
+    let paste_key = tenant_key("file", host, id);
+    let key = format!("{paste_key}/thumb/{width}");
+    let thumbnail = if let Some(found) = cache::core::lookup(key.clone().into()).execute()? {
+        found.to_stream()?.into_bytes()
+    } else {
+        let content = match get_paste(host, id, false, None, None, false, false) {
+            Ok((content, _)) => Body::from(content).into_bytes(),
+            Err(e) => return Ok(paste_error_response(id, e)),
+        };
+
+        let mut limits = image::Limits::default();
+        limits.max_alloc = Some(config::MAX_THUMBNAIL_ALLOC);
+        let mut reader =
+            image::ImageReader::new(std::io::Cursor::new(content)).with_guessed_format()?;
+        reader.limits(limits);
+        let Ok(image) = reader.decode() else {
+            return Ok(Response::from_status(415)
+                .with_body_text_plain(&format!("{id} could not be decoded as an image")));
+        };
+
+        // Images are never upscaled, and webp is only encoded from 8 bit rgba
+        let image = if image.width() > width || image.height() > width {
+            image.thumbnail(width, width)
+        } else {
+            image
+        };
+        let mut thumbnail = Vec::new();
+        image::DynamicImage::ImageRgba8(image.to_rgba8()).write_to(
+            &mut std::io::Cursor::new(&mut thumbnail),
+            image::ImageFormat::WebP,
+        )?;
+
+        // purged along with the paste
+        let mut w = cache::core::insert(key.into(), cache_ttl(&meta))
+            .surrogate_keys(["get", surrogate_key(host, id).as_str()])
+            .execute()?;
+        w.write_all(&thumbnail)?;
+        w.finish()?;
+        thumbnail
+    };
+
+    Ok(Response::from_body(thumbnail)
+        .with_header(header::CONTENT_TYPE, "image/webp")
+        .with_header(header::CACHE_CONTROL, paste_cache_control(&meta)))
+}
+
+/// Get the blake3 hash of a paste from its metadata, encoded as `?hash&enc=hex|base58|base64`.
+/// Base58 uses the id alphabet, so it's also the full hash lookup id.
+#[inline(always)]
+fn get_hash(req: &Request, host: &str, id: &str) -> Response {
+    let meta = match get_paste_meta(host, id)
+        .and_then(|meta| verify_signature(req, id, &meta).map(|_| meta))
+    {
+        Ok(meta) => meta,
+        Err(e) => return paste_error_response(id, e),
+    };
+
+    let hash = match req.get_query_parameter("enc").unwrap_or("base58") {
+        "hex" => blake3::Hash::from_bytes(meta.hash).to_hex().to_string(),
+        "base58" => encode_id(&meta.hash),
+        "base64" => base64::engine::general_purpose::STANDARD.encode(meta.hash),
+        _ => {
+            return Response::from_status(400)
+                .with_body_text_plain("expected ?enc=hex, ?enc=base58, or ?enc=base64");
+        },
+    };
+
+    Response::from_body(format!("{hash}\n"))
+        .with_header(header::CONTENT_TYPE, mime::TEXT_PLAIN_UTF_8.as_ref())
+        .with_header(
+            header::CACHE_CONTROL,
+            "public, s-maxage=31536000, immutable",
+        )
+        .with_header(
+            header::ETAG,
+            format!(r#""{}""#, bs58::encode(meta.hash).into_string()),
+        )
+}
 
-            Ok(Response::new().with_body_text_plain(PRIVACY))
-        },
+/// Pick the most preferred translated usage page from an accept-language header, defaulting to
+/// english.
+#[inline(always)]
+fn negotiate_language(accept_language: Option<&str>) -> &'static str {
+    const LANGUAGES: &[&str] = &["en", "de", "es"];
 
-        // Robots
-        Some("robots.txt") => {
-            const ROBOTS: &str = include_str!("static/robots.txt");
-            Ok(Response::new().with_body_text_plain(ROBOTS))
-        },
+    let mut candidates: Vec<(&str, f32)> = accept_language
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|part| {
+            let mut params = part.trim().split(';');
+            // only compare the primary subtag, ie `de` from `de-CH`
+            let tag = params.next()?.split('-').next()?.trim();
+            let quality = params
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse().ok())?;
+            Some((tag, quality))
+        })
+        .collect();
+    candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
 
-        // Favicon
-        Some("favicon.ico") => {
-            const FAVICON: &[u8] = include_bytes!("static/icons8-paste-special.png");
-            Ok(Response::from_body(FAVICON).with_content_type(mime::IMAGE_PNG))
-        },
+    candidates
+        .into_iter()
+        .filter(|(_, quality)| *quality > 0.0)
+        .find_map(|(tag, _)| LANGUAGES.iter().find(|l| l.eq_ignore_ascii_case(tag)))
+        .unwrap_or(&LANGUAGES[0])
+}
 
-        // JSON information page
-        Some("json") => {
-            let kv = KVStore::open(config::KV_STORE)?.unwrap();
-            let cnt = get_upload_count(&kv);
-            let json = serde_json::to_string_pretty(&json!({
-                "uploads": cnt,
-                "id_size": config::ID_SIZE,
-                "kv_ttl": format_duration(config::KV_TTL).to_string(),
-                "cache_ttl": format_duration(config::CACHE_TTL).to_string()
-            }))?;
-            Ok(Response::from_body(json).with_content_type(mime::APPLICATION_JSON))
-        },
+/// Handle a request to the usage page
+#[inline(always)]
+fn get_usage(host: &str, is_browser: bool, language: &str) -> Result<String, Error> {
+    // Build the man page style header and footer, padded to the width of the template
+    let header_and_footer = |template: &str, title: &str| {
+        // Compute max line
+        let max_line = template.lines().map(|l| l.chars().count()).max().unwrap() + 2;
 
-        // Paste download
-        Some("p") => {
-            let Some(id) = segments.next() else {
-                return Ok(Response::from_status(404).with_body_text_plain("expected paste id"));
-            };
-            let is_markdown = req.get_query_str() == Some("md");
+        // Build header
+        let page = host.to_uppercase() + "(1)";
+        let title =
+            title.pad_to_width_with_alignment(max_line - 2 * page.len(), pad::Alignment::Middle);
+        let header = format!("{page}{title}{page}");
 
-            let last = segments.next_back();
-            let filename = last.unwrap_or({
-                if !is_markdown {
-                    "no bs pastebin"
-                } else {
-                    "no bs markdown"
-                }
-            });
+        // Build footer
+        let version = std::env!("CARGO_PKG_VERSION");
+        let mut footer = format!("{host} {version}");
+        let offset = footer.len() - page.len();
+        footer += &compile_time::date_str!().pad_to_width_with_alignment(
+            max_line - footer.len() - page.len() - offset,
+            pad::Alignment::Middle,
+        );
+        footer += &" ".repeat(offset);
+        footer += &page;
 
-            let Ok((content, meta)) = get_paste(id, is_markdown, &host, filename) else {
-                return Ok(
-                    Response::from_status(404).with_body_text_plain(&format!("{id} not found"))
-                );
-            };
+        (header, footer)
+    };
 
-            Ok(Response::from_body(content)
-                // Immutable client caching
-                .with_header(
-                    // Client-side cache control, content will never change
-                    header::CACHE_CONTROL,
-                    "public, s-maxage=31536000, immutable",
-                )
-                // Content type and disposition (for "filename" on certain browsers)
-                .with_header(header::CONTENT_TYPE, meta.mime())
-                // Some browsers will set the title to this header
-                .with_header(
-                    header::CONTENT_DISPOSITION,
-                    format!(
-                        r#"inline; filename="{filename}"; filename*=UTF-8''{}"#,
-                        urlencoding::encode(filename)
-                    ),
-                ))
-        },
+    // Get upload counter, and the approximate storage used by every host
+    let kv = open_kv(config::KV_STORE)?;
+    let upload_counter = get_upload_count(&kv, host);
+    let storage_used = get_stored_bytes(&kv);
 
-        // Unknown path
-        Some(p) => Ok(Response::from_status(404).with_body_text_plain(&format!("{p} not found"))),
-        None => unreachable!(),
+    // Render a usage template, with its translated title and browser usage line
+    macro_rules! render {
+        ($template:literal, $title:literal, $browser_usage:literal) => {{
+            let (header, footer) = header_and_footer(include_str!($template), $title);
+            format!(
+                include_str!($template),
+                header = header,
+                host = host,
+                extra_usage = if is_browser { $browser_usage } else { "" },
+                max_size = humanize_bytes_binary!(config::MAX_CONTENT_SIZE),
+                kv_ttl = format_duration(config::KV_TTL).to_string(),
+                cache_ttl = format_duration(config::CACHE_TTL).to_string(),
+                upload_counter = upload_counter,
+                storage_used = humanize_bytes_binary!(storage_used),
+                footer = footer,
+            )
+        }};
     }
+
+    Ok(match language {
+        "de" => render!(
+            "templates/usage.de.txt",
+            "Benutzerbefehle",
+            "     * Im Webbrowser    :  <Strg/Cmd + V> drücken\n"
+        ),
+        "es" => render!(
+            "templates/usage.es.txt",
+            "Órdenes de usuario",
+            "     * Navegador web      :  Pulsa <Ctrl/Cmd + V>\n"
+        ),
+        _ => render!(
+            "templates/usage.txt",
+            "User Commands",
+            "     * Web browser    :  Press <Ctrl/Cmd + V>\n"
+        ),
+    })
 }
 
-/// Handle a request to the usage page
+/// Highlight the headings, commands, and urls of a usage page with ansi colors for terminals
 #[inline(always)]
-fn get_usage(host: &str, is_browser: bool) -> Result<String, Error> {
-    const USAGE_TEMPLATE: &str = include_str!("templates/usage.txt");
-
-    // Compute max line
-    let max_line = USAGE_TEMPLATE.lines().map(|l| l.len()).max().unwrap() + 2;
-
-    // Build header
-    let page = host.to_uppercase() + "(1)";
-    let title = "User Commands"
-        .pad_to_width_with_alignment(max_line - 2 * page.len(), pad::Alignment::Middle);
-    let header = format!("{page}{title}{page}");
-
-    // Build footer
-    let version = std::env!("CARGO_PKG_VERSION");
-    let mut footer = format!("{host} {version}");
-    let offset = footer.len() - page.len();
-    footer += &compile_time::date_str!().pad_to_width_with_alignment(
-        max_line - footer.len() - page.len() - offset,
-        pad::Alignment::Middle,
-    );
-    footer += &" ".repeat(offset);
-    footer += &page;
+fn colorize_usage(usage: &str, host: &str) -> String {
+    const BOLD: &str = "\x1b[1m";
+    const GREEN: &str = "\x1b[32m";
+    const CYAN: &str = "\x1b[36m";
+    const RESET: &str = "\x1b[0m";
 
-    // Get upload counter
-    let kv = KVStore::open(config::KV_STORE)?.expect("kv store to exist");
-    let upload_counter = get_upload_count(&kv);
+    let mut out = String::with_capacity(usage.len() * 2);
+    let mut lines = usage.lines().peekable();
+    while let Some(line) = lines.next() {
+        let trimmed = line.trim();
+        let is_heading = !trimmed.is_empty()
+            && trimmed.chars().all(|c| c.is_uppercase() || c == ' ')
+            && trimmed.chars().any(char::is_alphabetic);
+        // the man page style header and footer
+        let is_frame = out.is_empty() || lines.peek().is_none();
 
-    Ok(format!(
-        include_str!("templates/usage.txt"),
-        header = header,
-        host = host,
-        extra_usage = if is_browser {
-            "     * Web browser    :  Press <Ctrl/Cmd + V>\n"
+        if is_heading || is_frame {
+            out.push_str(&format!("{BOLD}{line}{RESET}"));
         } else {
-            ""
-        },
-        max_size = humanize_bytes_binary!(config::MAX_CONTENT_SIZE),
-        kv_ttl = format_duration(config::KV_TTL).to_string(),
-        cache_ttl = format_duration(config::CACHE_TTL).to_string(),
-        upload_counter = upload_counter,
-        footer = footer,
-    ))
+            let words: Vec<_> = line
+                .split(' ')
+                .map(|word| {
+                    let end = word.trim_end_matches(['.', ',', ')', ':', '"']);
+                    let start = end.trim_start_matches(['(', '"']);
+                    let color = if start == "curl" {
+                        GREEN
+                    } else if start.len() > 1
+                        && (start.starts_with(['?', '/'])
+                            || start.starts_with("https://")
+                            || start.starts_with(host))
+                    {
+                        CYAN
+                    } else {
+                        return word.to_string();
+                    };
+                    let lead = end.len() - start.len();
+                    format!(
+                        "{}{color}{start}{RESET}{}",
+                        &word[..lead],
+                        &word[end.len()..]
+                    )
+                })
+                .collect();
+            out.push_str(&words.join(" "));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Look up a paste in the kv store, where only a missing key means the paste isn't found
+#[inline(always)]
+fn lookup_paste(kv: &KVStore, key: &str) -> Result<fastly::kv_store::LookupResponse, PasteError> {
+    kv.lookup(key).map_err(|e| match e {
+        KVStoreError::ItemNotFound => PasteError::NotFound,
+        KVStoreError::StoreNotFound(_) => PasteError::Unavailable,
+        e => e.into(),
+    })
+}
+
+/// Get a paste's metadata from the cache or kv store, without reading the content.
+#[inline(always)]
+fn get_paste_meta(host: &str, id: &str) -> Result<FileMetadata<'static>, PasteError> {
+    let key = tenant_key("file", host, id);
+
+    let kv = kv_for(id).map_err(|_| PasteError::Unavailable)?;
+    if is_blocked(&kv, host, id) {
+        return Err(PasteError::Blocked);
+    }
+
+    let meta_bytes = match cache::core::lookup(key.clone().into()).execute()? {
+        Some(found) => found.user_metadata(),
+        None => lookup_paste(&kv, &key)?.metadata().unwrap(),
+    };
+    let meta: FileMetadata = serde_json::from_slice(&meta_bytes).expect("corrupted metadata");
+    if meta.deleted.is_some() {
+        return Err(PasteError::Deleted);
+    }
+    if is_expired(&meta) {
+        return Err(PasteError::NotFound);
+    }
+    Ok(meta)
 }
 
 /// Get immutable content from the cache, or fallback to kv store and insert to cache.
+///
+/// Compressed content is decoded unless the client accepts the stored encoding and the content
+/// is served as is, in which case the metadata keeps the encoding for the response headers.
 #[inline(always)]
 fn get_paste(
-    id: &str,
-    is_markdown: bool,
     host: &str,
-    filename: &str,
-) -> Result<(BodyHandle, FileMetadata<'static>), Error> {
-    let key = "file_".to_string() + id;
-
-    // Try to find content in cache
-    let string;
-    let mut meta;
-    if let Some(found) = cache::core::lookup(key.clone().into()).execute()? {
-        meta = serde_json::from_slice(&found.user_metadata()).expect("corrupted metadata");
+    id: &str,
+    accept_encoded: bool,
+    markdown: Option<&Page>,
+    cols: Option<usize>,
+    plain: bool,
+    render: bool,
+) -> Result<(BodyHandle, FileMetadata<'static>), PasteError> {
+    let key = tenant_key("file", host, id);
 
-        if !is_markdown {
-            return Ok((found.to_stream()?.into_handle(), meta));
-        }
+    // Blocked pastes are kept in storage, but never served
+    let kv = kv_for(id).map_err(|_| PasteError::Unavailable)?;
+    if is_blocked(&kv, host, id) {
+        return Err(PasteError::Blocked);
+    }
 
-        let mut buf = String::new();
-        found.to_stream()?.read_to_string(&mut buf)?;
-        string = buf;
+    // Try to find content in cache
+    let (body, mut meta): (Body, FileMetadata) = if let Some(found) =
+        cache::core::lookup(key.clone().into()).execute()?
+    {
+        let meta = serde_json::from_slice(&found.user_metadata()).expect("corrupted metadata");
+        (found.to_stream()?, meta)
     } else {
         // Otherwise, get content from key value store (origin)
-        let kv = KVStore::open(config::KV_STORE)?.expect("kv store to exist");
-        let mut res = kv.lookup(&key)?;
+        let mut res = lookup_paste(&kv, &key)?;
         let meta_bytes = res.metadata().unwrap();
-        meta = serde_json::from_slice(&meta_bytes).expect("corrupted metadata");
+        let meta: FileMetadata = serde_json::from_slice(&meta_bytes).expect("corrupted metadata");
+        // tombstones are never cached, so re-uploads are served right away
+        if meta.deleted.is_some() {
+            return Err(PasteError::Deleted);
+        }
         let content = res.take_body_bytes();
 
         // Write content & metadata to cache
-        let mut w = cache::core::insert(key.to_owned().into(), config::CACHE_TTL)
-            .surrogate_keys(["get"])
+        let mut w = cache::core::insert(key.to_owned().into(), cache_ttl(&meta))
+            .surrogate_keys(["get", surrogate_key(host, id).as_str()])
             .user_metadata(meta_bytes)
             .execute()?;
         w.write_all(&content)?;
         w.finish()?;
 
-        if !is_markdown {
-            return Ok((content.into(), meta));
+        (content.into(), meta)
+    };
+    // the cache keeps entries until their ttl, which can be rounded past the expiry
+    if is_expired(&meta) {
+        return Err(PasteError::NotFound);
+    }
+
+    // Decrypt content encrypted at rest, which is still cached encrypted
+    let body: Body = match meta.nonce.take() {
+        Some(nonce) => decrypt_content(&body.into_bytes(), &nonce)?.into(),
+        None => body,
+    };
+
+    // encrypted content is never rendered, and plain text overrides the stored type
+    let plain = is_served_plain(&meta.mime, plain, render);
+    if plain && meta.encrypted.is_none() {
+        meta.mime = Cow::from(mime::TEXT_PLAIN_UTF_8.as_ref());
+    }
+
+    // Text stored before the charset was detected at upload is still labeled, so browsers
+    // don't guess
+    let body: Body = if meta.encrypted.is_none()
+        && meta.mime.starts_with("text/")
+        && !meta.mime.contains("charset=")
+    {
+        let bytes = body.into_bytes();
+        let charset = match meta.encoding.as_deref() {
+            None => detect_charset(&bytes),
+            encoding => decode_content(bytes.clone().into(), encoding)
+                .ok()
+                .and_then(|content| detect_charset(&content)),
+        };
+        if let Some(charset) = charset {
+            meta.mime = Cow::from(format!("{}; charset={charset}", meta.mime));
+        }
+        bytes.into()
+    } else {
+        body
+    };
+    let page = markdown.filter(|_| meta.encrypted.is_none() && !plain);
+
+    // Rendered markdown is cached brotli compressed, without its scripts since they carry the
+    // nonce of each response
+    let render_key = page.map(|page| {
+        format!(
+            "{key}/md/{}/{}{}/{}",
+            page.theme,
+            page.toc as u8,
+            page.math as u8,
+            urlencoding::encode(page.title.unwrap_or_default())
+        )
+    });
+    if let (Some(page), Some(render_key)) = (page, &render_key) {
+        if let Some(found) = cache::core::lookup(render_key.clone().into()).execute()? {
+            let mermaid = found.user_metadata().as_ref() == b"mermaid";
+            let body = serve_rendered(found.to_stream()?, page, mermaid, &mut meta)?;
+            return Ok((body, meta));
         }
+    }
 
-        string = String::from_utf8_lossy(&content).to_string();
+    let cols = cols.filter(|_| meta.encrypted.is_none() && meta.mime.starts_with("text/"));
+    if page.is_none() && cols.is_none() && (meta.encoding.is_none() || accept_encoded) {
+        return Ok((body.into_handle(), meta));
     }
 
-    // render markdown
-    meta.mime = Cow::from("text/html");
-    let content = markdown::to_html_with_options(&string, &markdown::Options::gfm())
+    let content = decode_content(body, meta.encoding.take().as_deref())?;
+    let Some(page) = page else {
+        // binary content is left untouched
+        return Ok(match (cols, std::str::from_utf8(&content)) {
+            (Some(cols), Ok(text)) => (wrap_lines(text, cols).into(), meta),
+            _ => (content.into(), meta),
+        });
+    };
+    let (html, mermaid) = render_markdown(&content, host, id, page, &meta)?;
+
+    // purged along with the paste
+    let mut compressed = Vec::new();
+    {
+        let mut w = brotli::CompressorWriter::new(&mut compressed, 4096, 9, 22);
+        w.write_all(html.as_bytes())?;
+    }
+    if let Some(render_key) = render_key {
+        let mut w = cache::core::insert(render_key.into(), cache_ttl(&meta))
+            .surrogate_keys(["get", surrogate_key(host, id).as_str()])
+            .user_metadata(if mermaid { "mermaid" } else { "" }.into())
+            .execute()?;
+        w.write_all(&compressed)?;
+        w.finish()?;
+    }
+    let body = serve_rendered(compressed.into(), page, mermaid, &mut meta)?;
+    Ok((body, meta))
+}
+
+/// Render a markdown document as a page, with a placeholder for the scripts of any client side
+/// renderers it uses. Returns the page, and whether it has mermaid diagrams.
+#[inline(always)]
+fn render_markdown(
+    content: &[u8],
+    host: &str,
+    id: &str,
+    page: &Page,
+    meta: &FileMetadata,
+) -> Result<(String, bool), PasteError> {
+    // binary content is never fed to the markdown parser
+    let Ok(text) = std::str::from_utf8(content) else {
+        return Err(PasteError::NotText);
+    };
+
+    // math only when asked for since dollar signs are common in prose
+    let options = markdown::Options {
+        parse: markdown::ParseOptions {
+            constructs: markdown::Constructs {
+                math_flow: page.math,
+                math_text: page.math,
+                ..markdown::Constructs::gfm()
+            },
+            ..markdown::ParseOptions::gfm()
+        },
+        ..markdown::Options::gfm()
+    };
+    let mut content = markdown::to_html_with_options(text, &options)
         .unwrap_or_else(|e| format!("Failed to parse github flavored markdown: {e}"));
+
+    // Prepend a table of contents for long documents
+    let mut headings = Vec::new();
+    if let Ok(tree) = markdown::to_mdast(text, &options.parse) {
+        collect_headings(&tree, &mut headings);
+    }
+    if !headings.is_empty() && (page.toc || headings.len() >= config::TOC_MIN_HEADINGS) {
+        content = add_table_of_contents(&content, &headings);
+    }
+    // Untitled documents are named after their first top level heading
+    let heading = headings
+        .iter()
+        .find(|(depth, text)| *depth == 1 && !text.trim().is_empty())
+        .map(|(_, text)| text.trim());
+
+    // Client side renderers are only loaded for documents using them
+    let mermaid = content.contains(r#"<code class="language-mermaid">"#);
+    let scripts = if page.math || mermaid {
+        SCRIPTS_MARKER
+    } else {
+        ""
+    };
+
     let html = format!(
         include_str!("templates/markdown.html"),
-        filename = filename,
-        host = host,
-        content = content
+        canonical = canonical_tag(canonical_url(host, id, page.title, meta).as_deref()),
+        title = htmlescape::encode_minimal(
+            meta.title
+                .as_deref()
+                .or(heading)
+                .or(page.title)
+                .or(meta.filename.as_deref())
+                .unwrap_or("no bs markdown")
+        ),
+        host = page.host,
+        theme = page.theme,
+        content = content,
+        scripts = scripts,
     );
-    Ok((html.into(), meta))
+    Ok((html, mermaid))
+}
+
+/// Placeholder for the scripts of a rendered page, replaced with scripts carrying the nonce of
+/// each response. Markdown escapes raw html, so documents can't contain it themselves.
+const SCRIPTS_MARKER: &str = "<!-- scripts -->";
+
+/// Serve a brotli compressed rendered page as is if the client accepts it and it has no scripts,
+/// otherwise decoded with its scripts given the response nonce
+#[inline(always)]
+fn serve_rendered(
+    compressed: Body,
+    page: &Page,
+    mermaid: bool,
+    meta: &mut FileMetadata,
+) -> Result<BodyHandle, Error> {
+    meta.mime = Cow::from("text/html");
+    let has_scripts = page.math || mermaid;
+    if !has_scripts && page.brotli {
+        meta.encoding = Some("br".into());
+        return Ok(compressed.into_handle());
+    }
+    meta.encoding = None;
+    let html = decode_content(compressed, Some("br"))?;
+    if !has_scripts {
+        return Ok(Body::from(html).into_handle());
+    }
+    let html = with_scripts(&String::from_utf8_lossy(&html), page, mermaid);
+    Ok(Body::from(html).into_handle())
+}
+
+/// Replace the scripts placeholder of a rendered page with the renderers it uses
+#[inline(always)]
+fn with_scripts(html: &str, page: &Page, mermaid: bool) -> String {
+    html.replacen(SCRIPTS_MARKER, &renderer_scripts(page, mermaid), 1)
+}
+
+/// Get the client side renderer scripts of a page, carrying its nonce
+#[inline(always)]
+fn renderer_scripts(page: &Page, mermaid: bool) -> String {
+    let mut scripts = String::new();
+    if page.math {
+        scripts += &katex_assets(page.nonce);
+    }
+    if mermaid {
+        scripts += &mermaid_assets(page.nonce, page.theme);
+    }
+    scripts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Page options of a browser without any view flags
+    fn page() -> Page<'static> {
+        Page {
+            host: "0dd.sh",
+            title: None,
+            theme: "",
+            toc: false,
+            math: false,
+            nonce: "abc123",
+            brotli: false,
+        }
+    }
+
+    /// Check that every script element in a page carries the csp nonce, otherwise it's blocked
+    fn scripts_carry_nonce(html: &str, nonce: &str) -> bool {
+        let tagged = format!(r#"<script nonce="{nonce}""#);
+        html.to_ascii_lowercase().matches("<script").count() == html.matches(&tagged).count()
+    }
+
+    #[test]
+    fn strip_exif_removes_metadata() {
+        // one byte app0, quantization table, frame and scan segments, just enough to parse as a jpeg
+        let jpeg = Bytes::from_static(
+            b"\xff\xd8\xff\xe0\0\x03\0\xff\xdb\0\x03\0\xff\xc0\0\x03\0\xff\xda\0\x03\0\0\xff\xd9",
+        );
+        let mut image = DynImage::from_bytes(jpeg).unwrap().unwrap();
+        image.set_exif(Some(Bytes::from_static(b"II*\0\x08\0\0\0\0\0\0\0\0\0")));
+        let tagged: Vec<u8> = image.encoder().bytes().into();
+        assert!(
+            DynImage::from_bytes(tagged.clone().into())
+                .unwrap()
+                .unwrap()
+                .exif()
+                .is_some()
+        );
+
+        let stripped = strip_exif(tagged.clone());
+        assert!(stripped.len() < tagged.len());
+        let image = DynImage::from_bytes(stripped.clone().into())
+            .unwrap()
+            .unwrap();
+        assert!(image.exif().is_none());
+    }
+
+    #[test]
+    fn strip_exif_keeps_other_content() {
+        assert_eq!(strip_exif(b"not an image".to_vec()), b"not an image");
+    }
+
+    #[test]
+    fn rendered_scripts_carry_nonce() {
+        let page = Page {
+            math: true,
+            ..page()
+        };
+        let meta = FileMetadata::new([0; 32], "text/markdown".to_string(), 0);
+        let text =
+            "# Title\n\n$x^2$\n\n```mermaid\ngraph TD; a-->b\n```\n\n<script>alert(1)</script>\n";
+        let (html, mermaid) = render_markdown(text.as_bytes(), "0dd.sh", "id", &page, &meta)
+            .ok()
+            .unwrap();
+        assert!(mermaid);
+        assert!(html.contains(SCRIPTS_MARKER));
+
+        let html = with_scripts(&html, &page, mermaid);
+        assert!(html.contains("katex.min.js") && html.contains("mermaid.min.js"));
+        assert!(scripts_carry_nonce(&html, page.nonce));
+    }
+
+    #[test]
+    fn negotiate_language_picks_preferred_translation() {
+        assert_eq!(negotiate_language(None), "en");
+        assert_eq!(negotiate_language(Some("de-CH, en;q=0.8")), "de");
+        assert_eq!(negotiate_language(Some("fr, es;q=0.5, en;q=0.4")), "es");
+        assert_eq!(negotiate_language(Some("en;q=0.1, DE;q=0.9")), "de");
+        // refused and malformed languages are skipped
+        assert_eq!(negotiate_language(Some("de;q=0, es;q=bad")), "en");
+        assert_eq!(negotiate_language(Some("fr, ja")), "en");
+    }
+
+    #[test]
+    fn accepts_encoding_respects_quality() {
+        assert!(accepts_encoding(Some("gzip, deflate, br"), "gzip"));
+        assert!(accepts_encoding(Some("deflate, GZIP;q=0.5"), "gzip"));
+        assert!(!accepts_encoding(Some("gzip;q=0, br"), "gzip"));
+        assert!(!accepts_encoding(Some("gzip; q=0.000"), "gzip"));
+        assert!(!accepts_encoding(Some("identity"), "br"));
+        assert!(!accepts_encoding(None, "gzip"));
+    }
+
+    #[test]
+    fn encode_content_only_when_smaller() {
+        let text = "the same line over and over\n".repeat(64);
+        let encoded = encode_content(text.as_bytes()).unwrap();
+        assert!(encoded.len() < text.len());
+        let mut decoded = String::new();
+        GzDecoder::new(encoded.as_slice())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+        assert!(encode_content(b"short").is_none());
+    }
+
+    /// Gzip compress bytes like a client compressing its upload
+    fn gzip(body: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn decode_upload_decompresses_gzip() {
+        let text = b"hello world\n".to_vec();
+        assert_eq!(decode_upload(gzip(&text), Some("gzip")).unwrap(), text);
+        assert_eq!(decode_upload(gzip(&text), Some("x-gzip")).unwrap(), text);
+        assert_eq!(decode_upload(text.clone(), None).unwrap(), text);
+        assert_eq!(decode_upload(text.clone(), Some("identity")).unwrap(), text);
+        assert_eq!(decode_upload(text, Some("br")).unwrap_err().0, 415);
+    }
+
+    #[test]
+    fn compressed_uploads_are_typed_by_their_decoded_content() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        assert_eq!(upload_mime(None, &gzip(&png), None), "application/gzip");
+        let decoded = decode_upload(gzip(&png), Some("gzip")).unwrap();
+        assert_eq!(upload_mime(None, &decoded, None), "image/png");
+
+        let decoded = decode_upload(gzip(b"hello world\n"), Some("gzip")).unwrap();
+        assert!(upload_mime(None, &decoded, None).starts_with("text/plain"));
+    }
+
+    #[test]
+    fn table_of_contents_links_unique_anchors() {
+        let html = "<h1>Intro</h1><p>a</p><h2>Intro</h2><h2>A &amp; B!</h2><h3>***</h3>";
+        let headings = [
+            (1, "Intro".to_string()),
+            (2, "Intro".to_string()),
+            (2, "A & B!".to_string()),
+            (3, "***".to_string()),
+        ];
+        let out = add_table_of_contents(html, &headings);
+        assert!(out.starts_with(r#"<details class="toc" open>"#));
+        for id in ["intro", "intro-1", "a-b", "section"] {
+            assert!(
+                out.contains(&format!(r##"<a href="#{id}">"##)),
+                "missing link to {id}"
+            );
+            assert!(
+                out.contains(&format!(r#" id="{id}">"#)),
+                "missing anchor {id}"
+            );
+        }
+        assert!(out.contains("<a href=\"#a-b\">A &amp; B!</a>"));
+        assert!(out.ends_with("<h3 id=\"section\">***</h3>"));
+    }
+
+    #[test]
+    fn shard_for_is_stable_per_id() {
+        for id in ["", "a", "Zx9", "11111111"] {
+            assert!(config::KV_SHARDS.contains(&shard_for(id)));
+            assert_eq!(shard_for(id), shard_for(id));
+        }
+    }
+
+    #[test]
+    fn shard_in_spreads_ids_evenly() {
+        let shards = ["a", "b", "c", "d"];
+        let mut counts = std::collections::BTreeMap::new();
+        for _ in 0..4000 {
+            // real ids, whose first character is skewed
+            let id = encode_id(&rand::random::<[u8; 8]>());
+            let shard = shard_in(&shards, &id);
+            assert_eq!(shard, shard_in(&shards, &id));
+            *counts.entry(shard).or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), shards.len());
+        assert!(
+            counts.values().all(|&n| (800..1200).contains(&n)),
+            "{counts:?}"
+        );
+    }
+
+    #[test]
+    fn tenant_key_namespaces_hosts() {
+        let (a, b) = (
+            tenant_key_with(true, "file", "a.example", "id"),
+            tenant_key_with(true, "file", "b.example", "id"),
+        );
+        assert_eq!(a, "file_a.example_id");
+        assert_ne!(a, b);
+
+        let (a, b) = (
+            tenant_key_with(false, "file", "a.example", "id"),
+            tenant_key_with(false, "file", "b.example", "id"),
+        );
+        assert_eq!(a, "file_id");
+        assert_eq!(a, b);
+
+        assert_eq!(
+            tenant_key("file", "a.example", "id"),
+            tenant_key_with(config::MULTI_TENANT, "file", "a.example", "id")
+        );
+    }
+
+    #[test]
+    fn sanitize_filename_escapes_header_breaking_characters() {
+        assert_eq!(sanitize_filename("notes.txt").as_deref(), Some("notes.txt"));
+        assert_eq!(
+            sanitize_filename("a\"b\\c\r\nd.log").as_deref(),
+            Some("a_b_c__d.log")
+        );
+        assert!(sanitize_filename(&"a".repeat(config::MAX_FILENAME_SIZE + 1)).is_none());
+    }
+
+    #[test]
+    fn byte_range_parses_single_ranges() {
+        assert_eq!(byte_range("bytes=0-4", 10), Some(Some(0..5)));
+        assert_eq!(byte_range("bytes=5-", 10), Some(Some(5..10)));
+        assert_eq!(byte_range("bytes=-3", 10), Some(Some(7..10)));
+        assert_eq!(byte_range("bytes=-30", 10), Some(Some(0..10)));
+        assert_eq!(byte_range("bytes=8-100", 10), Some(Some(8..10)));
+        // unsatisfiable
+        assert_eq!(byte_range("bytes=10-", 10), Some(None));
+        assert_eq!(byte_range("bytes=-0", 10), Some(None));
+        // ignored, so the full body is served
+        assert_eq!(byte_range("bytes=0-1,4-5", 10), None);
+        assert_eq!(byte_range("bytes=5-2", 10), None);
+        assert_eq!(byte_range("items=0-1", 10), None);
+        assert_eq!(byte_range("bytes=abc", 10), None);
+    }
+
+    #[test]
+    fn if_range_only_matches_strong_validators() {
+        let date = "Wed, 14 Oct 2026 00:00:00 GMT";
+        assert!(if_range_matches(None, None, None));
+        assert!(if_range_matches(
+            Some(r#""abc""#),
+            Some(r#""abc""#),
+            Some(date)
+        ));
+        assert!(if_range_matches(Some(date), Some(r#""abc""#), Some(date)));
+        assert!(!if_range_matches(
+            Some(r#""old""#),
+            Some(r#""abc""#),
+            Some(date)
+        ));
+        // transformed representations never resume, even by date
+        assert!(!if_range_matches(
+            Some(r#"W/"abc""#),
+            Some(r#"W/"abc""#),
+            Some(date)
+        ));
+        assert!(!if_range_matches(
+            Some(date),
+            Some(r#"W/"abc""#),
+            Some(date)
+        ));
+        assert!(!if_range_matches(Some(date), None, Some(date)));
+    }
+
+    #[test]
+    fn paste_etag_differs_per_encoding() {
+        let mut meta = FileMetadata::new([1; 32], "text/plain".to_string(), 0);
+        let identity = paste_etag(&meta);
+        meta.encoding = Some("gzip".into());
+        let gzip = paste_etag(&meta);
+        assert_ne!(identity, gzip);
+        assert!(identity.starts_with('"') && gzip.ends_with(r#"-gzip""#));
+    }
+
+    #[test]
+    fn encode_id_uses_configured_alphabet() {
+        let bytes = blake3::hash(b"hello world\n");
+        let id = encode_id(bytes.as_bytes());
+        assert!(id.len() >= config::ID_SIZE);
+        assert!(id.bytes().all(|c| config::ID_ALPHABET.contains(&c)));
+
+        assert_eq!(decode_id(&id).unwrap(), bytes.as_bytes());
+    }
+
+    #[test]
+    fn id_alphabets_round_trip_and_reject_each_other() {
+        let bytes = blake3::hash(b"hello world\n");
+        let flickr = encode_with(bs58::Alphabet::FLICKR, bytes.as_bytes());
+        assert_eq!(
+            decode_with(bs58::Alphabet::FLICKR, &flickr).unwrap(),
+            bytes.as_bytes()
+        );
+        // flickr shares the bitcoin characters in another order
+        assert_ne!(
+            flickr,
+            encode_with(bs58::Alphabet::BITCOIN, bytes.as_bytes())
+        );
+        assert_ne!(
+            decode_with(bs58::Alphabet::BITCOIN, &flickr).unwrap(),
+            bytes.as_bytes()
+        );
+
+        // a custom alphabet with `l` in place of `L`
+        let custom =
+            bs58::Alphabet::new(b"123456789ABCDEFGHJKlMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz")
+                .unwrap();
+        let id = encode_with(&custom, bytes.as_bytes());
+        assert_eq!(decode_with(&custom, &id).unwrap(), bytes.as_bytes());
+        assert!(decode_with(&custom, "L").is_none());
+        assert!(decode_with(bs58::Alphabet::BITCOIN, "l").is_none());
+    }
+
+    #[test]
+    fn trailing_slashes_are_ignored() {
+        let url = |path: &str| fastly::http::Url::parse(&format!("https://0dd.sh{path}")).unwrap();
+        assert_eq!(without_trailing_slash("0dd.sh", &url("/p/id")), None);
+        assert_eq!(
+            without_trailing_slash("0dd.sh", &url("/p/id/")).as_deref(),
+            Some("https://0dd.sh/p/id")
+        );
+        assert_eq!(
+            without_trailing_slash("0dd.sh", &url("/p/id/name.txt//?raw")).as_deref(),
+            Some("https://0dd.sh/p/id/name.txt?raw")
+        );
+
+        assert_eq!(paste_filename("/p/id"), None);
+        assert_eq!(paste_filename("/p/id/"), None);
+        assert_eq!(paste_filename("/p/id/name.txt"), Some("name.txt"));
+        assert_eq!(paste_filename("/p/id/name.txt/"), Some("name.txt"));
+    }
+
+    #[test]
+    fn canonical_url_uses_stored_filename() {
+        let mut meta = FileMetadata::new([0; 32], "text/plain".to_string(), 0);
+        assert_eq!(canonical_url("0dd.sh", "id", None, &meta), None);
+        meta.filename = Some("notes.txt".to_string());
+        assert_eq!(
+            canonical_url("0dd.sh", "id", None, &meta).as_deref(),
+            Some("https://0dd.sh/p/id/notes.txt")
+        );
+        // urls already naming the paste are canonical themselves
+        assert_eq!(
+            canonical_url("0dd.sh", "id", Some("other.txt"), &meta),
+            None
+        );
+    }
+
+    #[test]
+    fn shorten_target_accepts_single_http_urls() {
+        assert_eq!(
+            shorten_target(b"  https://example.com/a?b=c\n").as_deref(),
+            Some("https://example.com/a?b=c")
+        );
+        assert_eq!(
+            shorten_target(b"http://example.com").as_deref(),
+            Some("http://example.com/")
+        );
+        assert_eq!(shorten_target(b"ftp://example.com/file"), None);
+        assert_eq!(shorten_target(b"javascript:alert(1)"), None);
+        assert_eq!(shorten_target(b"https://a.example https://b.example"), None);
+        assert_eq!(shorten_target(b"not a url"), None);
+        assert_eq!(shorten_target(b"\xff\xfe"), None);
+        let long = format!("https://example.com/{}", "a".repeat(config::MAX_URL_SIZE));
+        assert_eq!(shorten_target(long.as_bytes()), None);
+    }
+
+    #[test]
+    fn detect_language_from_shebangs_and_keywords() {
+        assert_eq!(
+            detect_language("#!/usr/bin/env python3\nprint(1)"),
+            Some("python")
+        );
+        assert_eq!(detect_language("#!/bin/sh\necho hi"), Some("bash"));
+        assert_eq!(detect_language("#!/usr/bin/env node\n"), Some("javascript"));
+        assert_eq!(detect_language("diff --git a/x b/x\n"), Some("diff"));
+        assert_eq!(detect_language(r#"{"a": [1, 2]}"#), Some("json"));
+        assert_eq!(
+            detect_language("use std::io;\n\npub fn main() {\n    let mut s = String::new();\n}"),
+            Some("rust")
+        );
+        assert_eq!(
+            detect_language("package main\n\nimport (\n\t\"fmt\"\n)\n\nfunc main() {\n\tx := 1\n}"),
+            Some("go")
+        );
+    }
+
+    #[test]
+    fn detect_language_skips_unclear_text() {
+        assert_eq!(detect_language("just some notes\nabout nothing"), None);
+        assert_eq!(detect_language("{not json"), None);
+        // a single keyword isn't enough to highlight prose
+        assert_eq!(detect_language("let me know when you're free"), None);
+    }
+
+    #[test]
+    fn wrap_lines_hard_wraps_long_lines() {
+        assert_eq!(wrap_lines("abcdefgh\nab\n", 3), "abc\ndef\ngh\nab\n");
+        assert_eq!(wrap_lines("abc\n", 3), "abc\n");
+        assert_eq!(wrap_lines("abc", 3), "abc");
+        assert_eq!(wrap_lines("\n\nabcd", 2), "\n\nab\ncd");
+        // columns are characters, not bytes
+        assert_eq!(wrap_lines("äöüß", 2), "äö\nüß");
+    }
+
+    /// Sanitize an svg, as text for readable assertions
+    fn sanitized(svg: &str) -> String {
+        String::from_utf8(sanitize_svg(svg.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn sanitize_svg_removes_scripts_and_handlers() {
+        let svg = sanitized(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" onload="alert(1)"><script>alert(2)</script><foreignObject><p>x</p></foreignObject><a href="javascript:alert(3)"><circle r="1" onclick="alert(4)"/></a></svg>"#,
+        );
+        assert!(!svg.contains("alert"), "{svg}");
+        assert!(svg.contains("<circle r=\"1\"/>"), "{svg}");
+    }
+
+    #[test]
+    fn sanitize_svg_removes_external_references() {
+        let svg = sanitized(
+            r##"<svg><image href="https://example.com/a.png"/><use href="#shape"/><rect fill="url(https://example.com/x)" stroke="url(#grad)"/></svg>"##,
+        );
+        assert!(!svg.contains("example.com"), "{svg}");
+        assert!(svg.contains(r##"<use href="#shape"/>"##), "{svg}");
+        assert!(svg.contains(r##"stroke="url(#grad)""##), "{svg}");
+    }
+
+    #[test]
+    fn sanitize_svg_keeps_namespaced_attributes() {
+        let svg = sanitized(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" xmlns:inkscape="http://www.inkscape.org/namespaces/inkscape" xmlns:sodipodi="http://sodipodi.sourceforge.net/DTD/sodipodi-0.dtd" sodipodi:docname="drawing.svg"><sodipodi:namedview inkscape:zoom="1"/><g inkscape:label="Layer 1" inkscape:groupmode="layer"><text xml:space="preserve">hi</text><use xlink:href="#a"/><use xlink:href="https://example.com/b.svg#c"/></g></svg>"##,
+        );
+        assert!(
+            svg.contains(r#"xmlns:xlink="http://www.w3.org/1999/xlink""#),
+            "{svg}"
+        );
+        assert!(svg.contains(r#"inkscape:label="Layer 1""#), "{svg}");
+        assert!(svg.contains(r#"<text xml:space="preserve">"#), "{svg}");
+        assert!(svg.contains(r##"<use xlink:href="#a"/>"##), "{svg}");
+        assert!(!svg.contains("example.com"), "{svg}");
+
+        // every prefix of the output still resolves to a declared namespace
+        let mut reader = quick_xml::NsReader::from_str(&svg);
+        loop {
+            match reader.read_resolved_event().unwrap() {
+                (_, quick_xml::events::Event::Eof) => break,
+                (ns, quick_xml::events::Event::Start(e) | quick_xml::events::Event::Empty(e)) => {
+                    assert!(!matches!(ns, quick_xml::name::ResolveResult::Unknown(_)));
+                    for attr in e.attributes() {
+                        let (ns, _) = reader.resolve_attribute(attr.unwrap().key);
+                        assert!(!matches!(ns, quick_xml::name::ResolveResult::Unknown(_)));
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    #[test]
+    fn sanitize_svg_removes_animated_links() {
+        let svg = sanitized(
+            r##"<svg xmlns:xlink="http://www.w3.org/1999/xlink"><a href="#x"><set attributeName="href" to="javascript:alert(1)"/><animate attributeName="xlink:href" values="javascript:alert(2)"></animate><animate attributeName="opacity" values="0;1"/>x</a></svg>"##,
+        );
+        assert!(!svg.contains("alert"), "{svg}");
+        assert!(svg.contains(r#"attributeName="opacity""#), "{svg}");
+    }
+
+    #[test]
+    fn sanitize_svg_rejects_invalid_xml() {
+        assert!(sanitize_svg(b"<svg><g></svg>").is_none());
+        assert!(sanitize_svg(b"<svg><script>").is_none());
+    }
+
+    #[test]
+    fn render_markdown_rejects_binary() {
+        let meta = FileMetadata::new([0; 32], "application/octet-stream".to_string(), 0);
+        let rendered =
+            render_markdown(b"\x89PNG\r\n\x1a\n\xff\xfe", "0dd.sh", "id", &page(), &meta);
+        assert!(matches!(rendered, Err(PasteError::NotText)));
+
+        let (html, mermaid) = render_markdown(b"# Hi\n", "0dd.sh", "id", &page(), &meta)
+            .ok()
+            .unwrap();
+        assert!(html.contains("<h1>Hi</h1>") && !mermaid);
+        assert!(!html.contains(SCRIPTS_MARKER));
+    }
+
+    #[test]
+    fn html_is_served_plain_unless_rendered() {
+        assert!(is_served_plain("text/html; charset=utf-8", false, false));
+        assert!(is_served_plain("application/xhtml+xml", false, false));
+        assert!(!is_served_plain("text/html", false, true));
+        // .txt urls win over ?render
+        assert!(is_served_plain("text/html", true, true));
+        assert!(!is_served_plain("image/png", false, false));
+        assert!(!is_served_plain("text/markdown", false, false));
+    }
+
+    // needs the compute runtime for the kv store, ie viceroy as the test runner
+    #[cfg(target_arch = "wasm32")]
+    #[test]
+    fn count_view_counts_every_view() {
+        let mut meta = FileMetadata::new([2; 32], "text/plain".to_string(), 0);
+        // limited pastes are always counted, even without view tracking
+        meta.max_views = Some(u64::MAX);
+        let counts: Vec<_> = (0..50)
+            .map(|_| count_view("0dd.sh", "count-view-test", &meta))
+            .collect();
+        assert_eq!(counts, (1..=50).map(Some).collect::<Vec<_>>());
+        assert_eq!(get_views("0dd.sh", "count-view-test", &meta), Some(50));
+    }
+
+    #[test]
+    fn view_limited_json_is_never_stored() {
+        let mut meta = FileMetadata::new([2; 32], "text/plain".to_string(), 0);
+        assert!(json_cache_control(&meta).starts_with("public"));
+        meta.max_views = Some(1);
+        assert_eq!(json_cache_control(&meta), "private, no-store");
+        assert_eq!(paste_cache_control(&meta), "private, no-store");
+    }
+
+    #[test]
+    fn decode_upload_bounds_decompression() {
+        let bomb = gzip(&vec![0; config::MAX_CONTENT_SIZE + 1]);
+        assert!(bomb.len() < config::MAX_CONTENT_SIZE / 100);
+        assert_eq!(decode_upload(bomb, Some("gzip")).unwrap_err().0, 413);
+
+        let limit = vec![0; config::MAX_CONTENT_SIZE];
+        assert_eq!(
+            decode_upload(gzip(&limit), Some("gzip")).unwrap().len(),
+            limit.len()
+        );
+        assert_eq!(
+            decode_upload(b"not gzip".to_vec(), Some("gzip"))
+                .unwrap_err()
+                .0,
+            400
+        );
+    }
+
+    #[test]
+    fn strip_bom_only_from_text() {
+        assert_eq!(
+            strip_bom(b"\xEF\xBB\xBF#!/bin/sh\n".to_vec()),
+            b"#!/bin/sh\n"
+        );
+        assert_eq!(strip_bom(b"no bom".to_vec()), b"no bom");
+        // only the leading mark is removed
+        assert_eq!(strip_bom(b"a\xEF\xBB\xBF".to_vec()), b"a\xEF\xBB\xBF");
+        assert_eq!(
+            strip_bom(b"\xEF\xBB\xBF\xff\x00".to_vec()),
+            b"\xEF\xBB\xBF\xff\x00"
+        );
+    }
+
+    #[test]
+    fn normalize_line_endings_converts_to_lf() {
+        assert_eq!(normalize_line_endings("a\r\nb\rc\nd"), "a\nb\nc\nd");
+        assert_eq!(normalize_line_endings("\r\r\n"), "\n\n");
+        assert_eq!(normalize_line_endings("unix\n"), "unix\n");
+    }
+
+    #[test]
+    fn missing_store_is_reported_by_name() {
+        let e: Error = MissingStore("paste storage").into();
+        assert!(e.is::<MissingStore>());
+        assert_eq!(e.to_string(), "kv store paste storage is not configured");
+    }
+
+    // needs the compute runtime, ie viceroy as the test runner
+    #[cfg(target_arch = "wasm32")]
+    #[test]
+    fn open_kv_fails_for_unlinked_stores() {
+        assert!(open_kv("paste storage").is_ok());
+        let e = open_kv("unlinked store").err().unwrap();
+        assert!(e.is::<MissingStore>());
+    }
+
+    // needs the compute runtime for requests, ie viceroy as the test runner
+    #[cfg(target_arch = "wasm32")]
+    #[test]
+    fn requests_without_a_host_are_rejected() {
+        for method in [Method::GET, Method::PUT, Method::DELETE] {
+            let req = Request::new(method, "file:///p/abc");
+            let res = route(req, "abc123").unwrap();
+            assert_eq!(res.get_status(), 400);
+        }
+    }
+
+    // needs the compute runtime for requests, ie viceroy as the test runner
+    #[cfg(target_arch = "wasm32")]
+    #[test]
+    fn unlisted_uploads_are_flagged_by_query_or_header() {
+        assert!(is_unlisted(&Request::put(
+            "https://0dd.sh/?unpack&unlisted"
+        )));
+        assert!(is_unlisted(
+            &Request::put("https://0dd.sh/?unpack").with_header("x-unlisted", "true")
+        ));
+        assert!(!is_unlisted(
+            &Request::put("https://0dd.sh/?unpack").with_header("x-unlisted", "0")
+        ));
+    }
+
+    #[test]
+    fn parse_embed_origins_normalizes_origins() {
+        assert_eq!(parse_embed_origins(" * ").as_deref(), Some("*"));
+        assert_eq!(
+            parse_embed_origins("https://a.example, http://B.example:8080").as_deref(),
+            Some("https://a.example http://b.example:8080")
+        );
+        // paths, other schemes, and csp syntax are never allowed into the policy
+        assert_eq!(parse_embed_origins("https://a.example/page"), None);
+        assert_eq!(parse_embed_origins("javascript:alert(1)"), None);
+        assert_eq!(parse_embed_origins("https://a.example; script-src *"), None);
+        assert_eq!(parse_embed_origins(""), None);
+        let many = ["https://a.example"; config::MAX_EMBED_ORIGINS + 1].join(" ");
+        assert_eq!(parse_embed_origins(&many), None);
+    }
+
+    // needs the compute runtime for responses, ie viceroy as the test runner
+    #[cfg(target_arch = "wasm32")]
+    #[test]
+    fn rate_limited_reports_carry_retry_after() {
+        let res = too_many_reports();
+        assert_eq!(res.get_status(), 429);
+        let secs: u64 = res
+            .get_header_str(header::RETRY_AFTER)
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(secs > 0 && secs <= config::REPORT_PENALTY_TTL.as_secs());
+    }
+
+    #[test]
+    fn paste_length_matches_served_bytes() {
+        let mut meta = FileMetadata::new([0; 32], "text/plain".to_string(), 1000);
+        assert_eq!(paste_length(&meta), Some(1000));
+        // served as stored to clients accepting the encoding
+        meta.encoding = Some("gzip".into());
+        meta.encoded_size = Some(120);
+        assert_eq!(paste_length(&meta), Some(120));
+        // pastes stored before their encoded size was recorded are sent chunked
+        meta.encoded_size = None;
+        assert_eq!(paste_length(&meta), None);
+    }
+
+    #[test]
+    fn client_mime_only_when_plausible() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        if !config::RESPECT_CONTENT_TYPE {
+            assert_eq!(client_mime(Some("text/x-rust"), b"fn main() {}"), None);
+            return;
+        }
+        assert_eq!(
+            client_mime(Some("text/x-rust"), b"fn main() {}").as_deref(),
+            Some("text/x-rust")
+        );
+        assert_eq!(
+            client_mime(Some("application/json; charset=utf-8"), b"{}").as_deref(),
+            Some("application/json; charset=utf-8")
+        );
+        assert_eq!(
+            client_mime(Some("image/x-custom"), png).as_deref(),
+            Some("image/x-custom")
+        );
+        // generic, mismatched, or svg types fall back to detection
+        assert_eq!(client_mime(Some("application/octet-stream"), b"text"), None);
+        assert_eq!(client_mime(Some("text/plain"), png), None);
+        assert_eq!(client_mime(Some("video/mp4"), png), None);
+        assert_eq!(client_mime(Some("image/svg+xml"), b"<svg></svg>"), None);
+        assert_eq!(client_mime(Some("not a mime"), b"text"), None);
+        assert_eq!(client_mime(None, b"text"), None);
+    }
+
+    #[test]
+    fn disposition_filename_is_sanitized() {
+        let name = disposition_filename;
+        assert_eq!(
+            name(r#"attachment; filename="foo.log""#).as_deref(),
+            Some("foo.log")
+        );
+        assert_eq!(name("inline; FILENAME=bar.txt").as_deref(), Some("bar.txt"));
+        // the extended utf-8 name wins, and is percent encoded like a url segment
+        assert_eq!(
+            name(r#"attachment; filename="a.txt"; filename*=UTF-8''caf%C3%A9 menu.txt"#).as_deref(),
+            Some("caf%C3%A9%20menu.txt")
+        );
+        // directories are never kept
+        assert_eq!(
+            name(r#"attachment; filename="../../etc/passwd""#).as_deref(),
+            Some("passwd")
+        );
+        assert_eq!(
+            name(r#"attachment; filename="C:\\dir\\x.bat""#).as_deref(),
+            Some("x.bat")
+        );
+        assert_eq!(name(r#"attachment; filename="..""#), None);
+        assert_eq!(name("attachment; filename=\"a\rb\""), None);
+        assert_eq!(name("attachment"), None);
+    }
+
+    #[test]
+    fn slugify_first_line() {
+        assert_eq!(
+            slugify(b"\n  Fix the Build!\nsecond line").as_deref(),
+            Some("fix-the-build")
+        );
+        assert_eq!(slugify(b"## v1.2 -- notes").as_deref(), Some("v1-2-notes"));
+        assert_eq!(slugify(b"!!!\nlater"), None);
+        assert_eq!(slugify(b"\xff\xfe binary"), None);
+        assert_eq!(slugify(b"   \n\n"), None);
+        let slug = slugify("word ".repeat(100).as_bytes()).unwrap();
+        assert!(slug.len() <= config::MAX_SLUG_SIZE && !slug.ends_with('-'));
+    }
+
+    #[test]
+    fn sanitize_svg_removes_external_styles() {
+        let svg = sanitized(
+            r##"<svg><style>@import url(https://example.com/a.css);</style><style>rect { fill: URL( "https://example.com/x" ) }</style><style>rect { fill: url(#grad) }</style><style><![CDATA[@import "https://example.com/b.css";]]></style><rect style="background: url(https://example.com/y)"/><rect style="fill: \75rl(https://example.com/z)"/></svg>"##,
+        );
+        assert!(!svg.contains("example.com"), "{svg}");
+        assert!(svg.contains("rect { fill: url(#grad) }"), "{svg}");
+    }
+
+    #[test]
+    fn unsafe_svgs_are_found_by_type_or_content() {
+        let hidden = format!("<!--{}--><svg></svg>", " ".repeat(8192));
+        assert!(!is_svg(hidden.as_bytes()));
+        assert!(is_unsafe_svg(hidden.as_bytes(), "image/svg+xml") == config::SANITIZE_SVG);
+        assert!(is_unsafe_svg(b"<svg></svg>", "text/plain") == config::SANITIZE_SVG);
+        assert!(!is_unsafe_svg(b"hello", "text/plain"));
+    }
+
+    #[test]
+    fn parse_counts_reads_json_or_older_lines() {
+        let counts = parse_counts(br#"{"text/plain":2,"image/png":1}"#);
+        assert_eq!(counts.get("text/plain"), Some(&2));
+        let counts = parse_counts(b"text/plain\nimage/png\ntext/plain\n");
+        assert_eq!(counts.get("text/plain"), Some(&2));
+        assert_eq!(counts.get("image/png"), Some(&1));
+        assert!(parse_counts(b"").is_empty());
+    }
+
+    // needs the compute runtime for the kv store, ie viceroy as the test runner
+    #[cfg(target_arch = "wasm32")]
+    #[test]
+    fn increment_count_is_bounded() {
+        let kv = open_kv(config::KV_STORE).unwrap();
+        for name in ["a", "b", "a", "c", "d"] {
+            increment_count(&kv, "increment-count-test", name, 2).unwrap();
+        }
+        let counts = parse_counts(&kv.lookup("increment-count-test").unwrap().take_body_bytes());
+        assert_eq!(counts.get("a"), Some(&2));
+        assert_eq!(counts.get("b"), Some(&1));
+        assert_eq!(counts.get("other"), Some(&2));
+    }
 }